@@ -0,0 +1,320 @@
+use crate::at_parser::{AtParseError, AtValue, ParsedAtCommand};
+
+/// REF argument without the takeoff and emergency bits (bits 18, 20, 22, 24
+/// and 28 are always set)
+const REF_BASE: i32 = 290717696;
+const REF_EMERGENCY: i32 = 1 << 8;
+const REF_TAKEOFF: i32 = 1 << 9;
+
+// Flags of PCMD and PCMD_MAG
+const PCMD_PROGRESSIVE: i32 = 1;
+const PCMD_COMBINED_YAW: i32 = 1 << 1;
+const PCMD_ABSOLUTE_CONTROL: i32 = 1 << 2;
+
+/// Characters the drone cannot read in a string argument
+const INVALID_STRING_CHARS: [char; 3] = ['"', ',', '\r'];
+
+fn checked_string(value: &str) -> Result<String, AtParseError> {
+    if value.contains(INVALID_STRING_CHARS) {
+        return Err(AtParseError::InvalidString(String::from(value)));
+    }
+    Ok(String::from(value))
+}
+
+/// Modes of the CTRL command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtrlMode {
+    NoControl,
+    ArdroneUpdate,
+    PicUpdate,
+    LogsGet,
+    /// Asks for the config, sent on the control port
+    CfgGet,
+    /// Acknowledges a command, clears the command ACK bit of the drone state
+    Ack,
+    /// Asks for the custom configs
+    CustomCfgGet,
+}
+
+impl CtrlMode {
+    /// Returns the mode with this number
+    pub fn from_number(number: i32) -> Option<CtrlMode> {
+        match number {
+            0 => Some(CtrlMode::NoControl),
+            1 => Some(CtrlMode::ArdroneUpdate),
+            2 => Some(CtrlMode::PicUpdate),
+            3 => Some(CtrlMode::LogsGet),
+            4 => Some(CtrlMode::CfgGet),
+            5 => Some(CtrlMode::Ack),
+            6 => Some(CtrlMode::CustomCfgGet),
+            _ => None,
+        }
+    }
+
+    pub fn number(self) -> i32 {
+        match self {
+            CtrlMode::NoControl => 0,
+            CtrlMode::ArdroneUpdate => 1,
+            CtrlMode::PicUpdate => 2,
+            CtrlMode::LogsGet => 3,
+            CtrlMode::CfgGet => 4,
+            CtrlMode::Ack => 5,
+            CtrlMode::CustomCfgGet => 6,
+        }
+    }
+}
+
+/// A command sent to the drone. The sequence number is added when it is
+/// sent.
+/// ```
+/// use parrot_ar_drone::*;
+///
+/// let takeoff = AtCommand::Ref { takeoff: true, emergency: false };
+/// assert_eq!(takeoff.to_at(5), "AT*REF=5,290718208\r");
+///
+/// let parsed = parse_at_command("AT*CTRL=6,4,0").unwrap();
+/// assert_eq!(AtCommand::from_parsed(&parsed), Ok(AtCommand::Ctrl(CtrlMode::CfgGet)));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum AtCommand {
+    /// Takes off or lands. The drone toggles its emergency mode when
+    /// `emergency` is set after a REF without it, repeating the same REF
+    /// does nothing.
+    Ref { takeoff: bool, emergency: bool },
+    /// Moves the drone, all values are in [-1, 1]. Without `progressive` the
+    /// drone hovers and ignores the values.
+    Pcmd {
+        progressive: bool,
+        combined_yaw: bool,
+        roll: f32,
+        pitch: f32,
+        gaz: f32,
+        yaw: f32,
+    },
+    /// Same as Pcmd, relative to the controller (psi is its heading)
+    PcmdMag {
+        progressive: bool,
+        combined_yaw: bool,
+        absolute_control: bool,
+        roll: f32,
+        pitch: f32,
+        gaz: f32,
+        yaw: f32,
+        psi: f32,
+        psi_accuracy: f32,
+    },
+    /// Flat trim, only on the ground
+    Ftrim,
+    /// Calibrates a device (0 is the magnetometer), only in flight
+    Calib(i32),
+    /// Manual trim
+    Mtrim { theta: f32, phi: f32, yaw: f32 },
+    /// Sets a config key, see AtCommand::config
+    Config { key: String, value: String },
+    /// Selects the session, user and application of the next CONFIG, see
+    /// AtCommand::config_ids
+    ConfigIds {
+        session_id: String,
+        user_id: String,
+        application_id: String,
+    },
+    Ctrl(CtrlMode),
+    /// Keeps the connection alive
+    Comwdg,
+    /// LED animation, duration in seconds
+    Led { animation: i32, frequency: f32, duration: i32 },
+    /// Flight animation, duration in seconds (the `control:flight_anim`
+    /// config takes milliseconds instead)
+    Anim { animation: i32, duration: i32 },
+    /// Motor speeds in [0, 1023]
+    Pwm {
+        front_left: i32,
+        front_right: i32,
+        rear_left: i32,
+        rear_right: i32,
+    },
+    /// Follows the 2D tags seen by the camera
+    Aflight(bool),
+    Pmode(i32),
+    Misc(i32, i32, i32, i32),
+}
+
+impl AtCommand {
+    /// A CONFIG command. The key and the value are sent between quotes, so
+    /// they must not contain `"`, `,` or `\r`.
+    /// ```
+    /// use parrot_ar_drone::*;
+    ///
+    /// let config = AtCommand::config("control:altitude_max", "3000").unwrap();
+    /// assert_eq!(config.to_at(7), "AT*CONFIG=7,\"control:altitude_max\",\"3000\"\r");
+    ///
+    /// assert!(AtCommand::config("custom:name", "a,b").is_err());
+    /// assert!(AtCommand::config("custom:name", "\"quoted\"").is_err());
+    /// assert!(AtCommand::config("custom:name\r", "1").is_err());
+    /// ```
+    pub fn config(key: &str, value: &str) -> Result<AtCommand, AtParseError> {
+        Ok(AtCommand::Config { key: checked_string(key)?, value: checked_string(value)? })
+    }
+
+    /// A CONFIG_IDS command, the ids must not contain `"`, `,` or `\r`
+    pub fn config_ids(session_id: &str, user_id: &str, application_id: &str)
+                      -> Result<AtCommand, AtParseError> {
+        Ok(AtCommand::ConfigIds {
+            session_id: checked_string(session_id)?,
+            user_id: checked_string(user_id)?,
+            application_id: checked_string(application_id)?,
+        })
+    }
+
+    /// The name without the `AT*` prefix
+    pub fn name(&self) -> &'static str {
+        match self {
+            AtCommand::Ref { .. } => "REF",
+            AtCommand::Pcmd { .. } => "PCMD",
+            AtCommand::PcmdMag { .. } => "PCMD_MAG",
+            AtCommand::Ftrim => "FTRIM",
+            AtCommand::Calib(_) => "CALIB",
+            AtCommand::Mtrim { .. } => "MTRIM",
+            AtCommand::Config { .. } => "CONFIG",
+            AtCommand::ConfigIds { .. } => "CONFIG_IDS",
+            AtCommand::Ctrl(_) => "CTRL",
+            AtCommand::Comwdg => "COMWDG",
+            AtCommand::Led { .. } => "LED",
+            AtCommand::Anim { .. } => "ANIM",
+            AtCommand::Pwm { .. } => "PWM",
+            AtCommand::Aflight(_) => "AFLIGHT",
+            AtCommand::Pmode(_) => "PMODE",
+            AtCommand::Misc(..) => "MISC",
+        }
+    }
+
+    /// The arguments after the sequence number
+    pub fn args(&self) -> Vec<AtValue> {
+        use AtValue::*;
+        match self {
+            AtCommand::Ref { takeoff, emergency } => {
+                let mut value = REF_BASE;
+                if *takeoff {
+                    value |= REF_TAKEOFF;
+                }
+                if *emergency {
+                    value |= REF_EMERGENCY;
+                }
+                vec![Int(value)]
+            }
+            AtCommand::Pcmd { progressive, combined_yaw, roll, pitch, gaz, yaw } => {
+                let flags = pcmd_flags(*progressive, *combined_yaw, false);
+                vec![Int(flags), Float(*roll), Float(*pitch), Float(*gaz), Float(*yaw)]
+            }
+            AtCommand::PcmdMag {
+                progressive, combined_yaw, absolute_control,
+                roll, pitch, gaz, yaw, psi, psi_accuracy,
+            } => {
+                let flags = pcmd_flags(*progressive, *combined_yaw, *absolute_control);
+                vec![Int(flags), Float(*roll), Float(*pitch), Float(*gaz), Float(*yaw),
+                     Float(*psi), Float(*psi_accuracy)]
+            }
+            AtCommand::Ftrim | AtCommand::Comwdg => Vec::new(),
+            AtCommand::Calib(device) => vec![Int(*device)],
+            AtCommand::Mtrim { theta, phi, yaw } => vec![Float(*theta), Float(*phi), Float(*yaw)],
+            AtCommand::Config { key, value } => vec![Str(key.clone()), Str(value.clone())],
+            AtCommand::ConfigIds { session_id, user_id, application_id } => {
+                vec![Str(session_id.clone()), Str(user_id.clone()), Str(application_id.clone())]
+            }
+            AtCommand::Ctrl(mode) => vec![Int(mode.number()), Int(0)],
+            AtCommand::Led { animation, frequency, duration } => {
+                vec![Int(*animation), Float(*frequency), Int(*duration)]
+            }
+            AtCommand::Anim { animation, duration } => vec![Int(*animation), Int(*duration)],
+            AtCommand::Pwm { front_left, front_right, rear_left, rear_right } => {
+                vec![Int(*front_left), Int(*front_right), Int(*rear_left), Int(*rear_right)]
+            }
+            AtCommand::Aflight(on) => vec![Int(*on as i32)],
+            AtCommand::Pmode(mode) => vec![Int(*mode)],
+            AtCommand::Misc(a, b, c, d) => vec![Int(*a), Int(*b), Int(*c), Int(*d)],
+        }
+    }
+
+    /// Formats the command with the sequence number, ending with `\r`
+    pub fn to_at(&self, seq: u32) -> String {
+        let parsed = ParsedAtCommand {
+            name: String::from(self.name()),
+            seq,
+            args: self.args(),
+        };
+        format!("{}\r", parsed)
+    }
+
+    /// Returns the command a parsed AT command stands for
+    pub fn from_parsed(parsed: &ParsedAtCommand) -> Result<AtCommand, AtParseError> {
+        let wrong = |index| AtParseError::WrongArgument { command: parsed.to_string(), index };
+        let int = |index| parsed.int(index).ok_or_else(|| wrong(index));
+        let float = |index| parsed.float(index).ok_or_else(|| wrong(index));
+        let string = |index| parsed.string(index).map(String::from).ok_or_else(|| wrong(index));
+        let flag = |index: usize, bit: i32| int(index).map(|flags| flags & bit != 0);
+
+        let command = match parsed.name.as_str() {
+            "REF" => AtCommand::Ref {
+                takeoff: int(0)? & REF_TAKEOFF != 0,
+                emergency: int(0)? & REF_EMERGENCY != 0,
+            },
+            "PCMD" => AtCommand::Pcmd {
+                progressive: flag(0, PCMD_PROGRESSIVE)?,
+                combined_yaw: flag(0, PCMD_COMBINED_YAW)?,
+                roll: float(1)?,
+                pitch: float(2)?,
+                gaz: float(3)?,
+                yaw: float(4)?,
+            },
+            "PCMD_MAG" => AtCommand::PcmdMag {
+                progressive: flag(0, PCMD_PROGRESSIVE)?,
+                combined_yaw: flag(0, PCMD_COMBINED_YAW)?,
+                absolute_control: flag(0, PCMD_ABSOLUTE_CONTROL)?,
+                roll: float(1)?,
+                pitch: float(2)?,
+                gaz: float(3)?,
+                yaw: float(4)?,
+                psi: float(5)?,
+                psi_accuracy: float(6)?,
+            },
+            "FTRIM" => AtCommand::Ftrim,
+            "CALIB" => AtCommand::Calib(int(0)?),
+            "MTRIM" => AtCommand::Mtrim { theta: float(0)?, phi: float(1)?, yaw: float(2)? },
+            "CONFIG" => AtCommand::Config { key: string(0)?, value: string(1)? },
+            "CONFIG_IDS" => AtCommand::ConfigIds {
+                session_id: string(0)?,
+                user_id: string(1)?,
+                application_id: string(2)?,
+            },
+            "CTRL" => AtCommand::Ctrl(CtrlMode::from_number(int(0)?).ok_or_else(|| wrong(0))?),
+            "COMWDG" => AtCommand::Comwdg,
+            "LED" => AtCommand::Led { animation: int(0)?, frequency: float(1)?, duration: int(2)? },
+            "ANIM" => AtCommand::Anim { animation: int(0)?, duration: int(1)? },
+            "PWM" => AtCommand::Pwm {
+                front_left: int(0)?,
+                front_right: int(1)?,
+                rear_left: int(2)?,
+                rear_right: int(3)?,
+            },
+            "AFLIGHT" => AtCommand::Aflight(int(0)? != 0),
+            "PMODE" => AtCommand::Pmode(int(0)?),
+            "MISC" => AtCommand::Misc(int(0)?, int(1)?, int(2)?, int(3)?),
+            _ => return Err(AtParseError::UnknownCommand(parsed.to_string())),
+        };
+        Ok(command)
+    }
+}
+
+fn pcmd_flags(progressive: bool, combined_yaw: bool, absolute_control: bool) -> i32 {
+    let mut flags = 0;
+    if progressive {
+        flags |= PCMD_PROGRESSIVE;
+    }
+    if combined_yaw {
+        flags |= PCMD_COMBINED_YAW;
+    }
+    if absolute_control {
+        flags |= PCMD_ABSOLUTE_CONTROL;
+    }
+    flags
+}
@@ -0,0 +1,242 @@
+use std::error::Error;
+use std::fmt;
+
+/// An argument of an AT command
+#[derive(Debug, Clone, PartialEq)]
+pub enum AtValue {
+    Int(i32),
+    /// Sent as the integer with the same bits (see format_float)
+    Float(f32),
+    /// Sent between double quotes
+    Str(String),
+}
+
+impl fmt::Display for AtValue {
+    /// Formats the argument the way it is sent
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtValue::Int(value) => write!(f, "{}", value),
+            AtValue::Float(value) => write!(f, "{}", value.to_bits()),
+            AtValue::Str(value) => write!(f, "\"{}\"", value),
+        }
+    }
+}
+
+/// An AT command read back from a datagram, e.g. `AT*REF=5,290718208`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedAtCommand {
+    /// The name without the `AT*` prefix, e.g. "REF"
+    pub name: String,
+    pub seq: u32,
+    pub args: Vec<AtValue>,
+}
+
+impl ParsedAtCommand {
+    /// The argument at `index` if it is an integer
+    pub fn int(&self, index: usize) -> Option<i32> {
+        match self.args.get(index) {
+            Some(AtValue::Int(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The argument at `index` if it is a float
+    pub fn float(&self, index: usize) -> Option<f32> {
+        match self.args.get(index) {
+            Some(AtValue::Float(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The argument at `index` if it is a string
+    pub fn string(&self, index: usize) -> Option<&str> {
+        match self.args.get(index) {
+            Some(AtValue::Str(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ParsedAtCommand {
+    /// Formats the command the way it is sent, without the trailing `\r`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AT*{}={}", self.name, self.seq)?;
+        for arg in &self.args {
+            write!(f, ",{}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why an AT command couldn't be parsed, with the command text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AtParseError {
+    /// The command does not start with `AT*`
+    MissingPrefix(String),
+    /// There is no `=` and sequence number after the name
+    MissingSequence(String),
+    /// The sequence number is not a number
+    WrongSequence(String),
+    /// An argument does not have the type the command expects
+    WrongArgument { command: String, index: usize },
+    /// A string argument has no closing quote
+    UnterminatedString(String),
+    /// A string argument contains `"`, `,` or `\r`, which the drone cannot
+    /// read
+    InvalidString(String),
+    /// The command is not one of AtCommand
+    UnknownCommand(String),
+}
+
+impl fmt::Display for AtParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtParseError::MissingPrefix(command) => {
+                write!(f, "AT command does not start with AT*: {}", command)
+            }
+            AtParseError::MissingSequence(command) => {
+                write!(f, "AT command has no sequence number: {}", command)
+            }
+            AtParseError::WrongSequence(command) => {
+                write!(f, "AT command has wrong sequence number: {}", command)
+            }
+            AtParseError::WrongArgument { command, index } => {
+                write!(f, "AT command has wrong argument {}: {}", index, command)
+            }
+            AtParseError::UnterminatedString(command) => {
+                write!(f, "AT command has unterminated string: {}", command)
+            }
+            AtParseError::InvalidString(value) => {
+                write!(f, "AT string argument contains '\"', ',' or '\\r': {:?}", value)
+            }
+            AtParseError::UnknownCommand(command) => {
+                write!(f, "unknown AT command: {}", command)
+            }
+        }
+    }
+}
+
+impl Error for AtParseError {}
+
+#[derive(Clone, Copy)]
+enum ArgKind {
+    Int,
+    Float,
+    Str,
+}
+
+/// Types of the arguments of the commands sent by this crate, the others
+/// are read as integers or strings
+fn signature(name: &str) -> &'static [ArgKind] {
+    use ArgKind::*;
+    match name {
+        "REF" | "CALIB" | "AFLIGHT" | "PMODE" => &[Int],
+        "PCMD" => &[Int, Float, Float, Float, Float],
+        "PCMD_MAG" => &[Int, Float, Float, Float, Float, Float, Float],
+        "MTRIM" => &[Float, Float, Float],
+        "CONFIG" => &[Str, Str],
+        "CONFIG_IDS" => &[Str, Str, Str],
+        "CTRL" => &[Int, Int],
+        "LED" => &[Int, Float, Int],
+        "ANIM" => &[Int, Int],
+        "PWM" | "MISC" => &[Int, Int, Int, Int],
+        _ => &[],
+    }
+}
+
+/// Splits the arguments at the commas which are not in a string
+fn split_args(command: &str, args: &str) -> Result<Vec<(String, bool)>, AtParseError> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut was_quoted = false;
+    for c in args.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                was_quoted = true;
+            }
+            ',' if !quoted => {
+                parts.push((current.clone(), was_quoted));
+                current.clear();
+                was_quoted = false;
+            }
+            c => current.push(c),
+        }
+    }
+    if quoted {
+        return Err(AtParseError::UnterminatedString(String::from(command)));
+    }
+    parts.push((current, was_quoted));
+    Ok(parts)
+}
+
+/// Integers may be sent signed or unsigned (e.g. the bits of a float)
+fn parse_bits(arg: &str) -> Option<i32> {
+    arg.parse::<i32>().ok()
+        .or_else(|| arg.parse::<u32>().ok().map(|bits| bits as i32))
+}
+
+/// Parses one AT command, with or without the trailing `\r`.
+/// ```
+/// use parrot_ar_drone::*;
+///
+/// let pcmd = parse_at_command("AT*PCMD=7,1,0,3204448256,0,0\r").unwrap();
+/// assert_eq!(pcmd.name, "PCMD");
+/// assert_eq!(pcmd.seq, 7);
+/// assert_eq!(pcmd.float(2), Some(-0.5));
+/// assert_eq!(pcmd.to_string(), "AT*PCMD=7,1,0,3204448256,0,0");
+/// ```
+pub fn parse_at_command(command: &str) -> Result<ParsedAtCommand, AtParseError> {
+    let text = command.trim_end_matches('\r');
+    let body = text.strip_prefix("AT*")
+        .ok_or_else(|| AtParseError::MissingPrefix(String::from(text)))?;
+    let (name, rest) = body.split_once('=')
+        .ok_or_else(|| AtParseError::MissingSequence(String::from(text)))?;
+    let (seq, args) = rest.split_once(',').unwrap_or((rest, ""));
+    let seq = seq.parse::<u32>()
+        .map_err(|_| AtParseError::WrongSequence(String::from(text)))?;
+
+    let mut parsed = ParsedAtCommand {
+        name: String::from(name),
+        seq,
+        args: Vec::new(),
+    };
+    if !rest.contains(',') {
+        return Ok(parsed);
+    }
+    let kinds = signature(name);
+    for (index, (arg, quoted)) in split_args(text, args)?.into_iter().enumerate() {
+        let wrong = || AtParseError::WrongArgument { command: String::from(text), index };
+        let value = match (kinds.get(index), quoted) {
+            (Some(ArgKind::Str), true) | (None, true) => AtValue::Str(arg),
+            (Some(ArgKind::Int), false) | (None, false) => {
+                AtValue::Int(parse_bits(&arg).ok_or_else(wrong)?)
+            }
+            (Some(ArgKind::Float), false) => {
+                AtValue::Float(f32::from_bits(parse_bits(&arg).ok_or_else(wrong)? as u32))
+            }
+            _ => return Err(wrong()),
+        };
+        parsed.args.push(value);
+    }
+    Ok(parsed)
+}
+
+/// Parses every AT command of a datagram, they are separated by `\r`.
+/// ```
+/// use parrot_ar_drone::*;
+///
+/// let datagram = b"AT*CONFIG=3,\"general:navdata_demo\",\"TRUE\"\rAT*CTRL=4,5,0\r";
+/// let commands = parse_at_commands(datagram).unwrap();
+/// assert_eq!(commands.len(), 2);
+/// assert_eq!(commands[0].string(1), Some("TRUE"));
+/// assert_eq!(commands[1].int(0), Some(5));
+/// ```
+pub fn parse_at_commands(datagram: &[u8]) -> Result<Vec<ParsedAtCommand>, AtParseError> {
+    let text = String::from_utf8_lossy(datagram);
+    text.split('\r')
+        .filter(|command| !command.is_empty())
+        .map(parse_at_command)
+        .collect()
+}
@@ -0,0 +1,779 @@
+//! A stand-in AR.Drone 2.0 for testing flight code without hardware.
+//!
+//! It listens for AT commands, sends navdata (with the bootstrap handshake,
+//! demo mode and the `general:navdata_options` blocks), serves the config on
+//! the control port and flies a simple kinematic model of the drone, so the
+//! altitude, the attitude and the speeds follow the commands.
+//!
+//! As the simulator uses the drone ports, a Drone on the same computer has
+//! to receive on other local ports:
+//!
+//! ```text
+//! let mut drone = DroneBuilder::new()
+//!     .drone_ip("127.0.0.1")
+//!     .ephemeral_ports()
+//!     .build();
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parrot_ar_drone::*;
+
+const USAGE: &str = "Usage: ardrone-sim [options]
+
+Options:
+    --bind <ip>             Local address to listen on (default 0.0.0.0)
+    --cmd-port <port>       UDP port of the AT commands (default 5556)
+    --navdata-port <port>   UDP port of the navdata (default 5554)
+    --control-port <port>   TCP port of the config (default 5559)
+    --verbose               Print every AT command received
+    --help                  Print this message";
+
+/// Time step of the model
+const STEP: Duration = Duration::from_millis(5);
+/// Navdata period in demo mode (15 packets per second)
+const DEMO_PERIOD: Duration = Duration::from_millis(66);
+/// Without any AT command for this long the connection is considered lost
+const COM_WATCHDOG_TIMEOUT: Duration = Duration::from_secs(2);
+
+const G: f32 = 9.81;
+/// Air drag while moving (1/s)
+const DRAG: f32 = 0.6;
+/// Drag of the position hold in hover mode (1/s)
+const HOVER_DRAG: f32 = 2.5;
+/// Time constant of the attitude (s)
+const ATTITUDE_TAU: f32 = 0.15;
+/// Time constant of the vertical speed (s)
+const VZ_TAU: f32 = 0.25;
+/// Altitude reached by the takeoff (m) and the climb rate (m/s)
+const TAKEOFF_ALTITUDE: f32 = 0.8;
+const TAKEOFF_SPEED: f32 = 0.7;
+const LANDING_SPEED: f32 = 0.5;
+
+struct Args {
+    bind: String,
+    cmd_port: u16,
+    navdata_port: u16,
+    control_port: u16,
+    verbose: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        bind: String::from("0.0.0.0"),
+        cmd_port: 5556,
+        navdata_port: 5554,
+        control_port: 5559,
+        verbose: false,
+    };
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| iter.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--bind" => { args.bind = value("--bind")?; }
+            "--cmd-port" => { args.cmd_port = parse_port(&value("--cmd-port")?)?; }
+            "--navdata-port" => { args.navdata_port = parse_port(&value("--navdata-port")?)?; }
+            "--control-port" => { args.control_port = parse_port(&value("--control-port")?)?; }
+            "--verbose" => { args.verbose = true; }
+            "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => { return Err(format!("unknown option {}", arg)); }
+        }
+    }
+    Ok(args)
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    port.parse().map_err(|_| format!("wrong port: {}", port))
+}
+
+/// Moves `value` towards `target` with the time constant `tau`
+fn approach(value: f32, target: f32, tau: f32, dt: f32) -> f32 {
+    value + (target - value) * (dt / tau).min(1.0)
+}
+
+/// The simulated drone
+struct Sim {
+    verbose: bool,
+    start: Instant,
+    state: ControlState,
+    emergency: bool,
+    /// Emergency bit of the last REF, the emergency toggles on its rising
+    /// edge
+    last_ref_emergency: bool,
+    /// PCMD: progressive flag, roll, pitch, gaz, yaw (all in [-1, 1])
+    progressive: bool,
+    roll: f32,
+    pitch: f32,
+    gaz: f32,
+    yaw: f32,
+    /// Position (m), world speeds (m/s), vertical speed (m/s, up)
+    x: f32,
+    y: f32,
+    z: f32,
+    vn: f32,
+    ve: f32,
+    vz: f32,
+    /// Attitude in degrees
+    theta: f32,
+    phi: f32,
+    psi: f32,
+    battery: f32,
+    bootstrap: bool,
+    command_ack: bool,
+    last_at_seq: u32,
+    last_command: Instant,
+    config: BTreeMap<String, String>,
+    control_clients: Vec<TcpStream>,
+    navdata_client: Option<SocketAddr>,
+    seq_num: u32,
+}
+
+impl Sim {
+    fn new(verbose: bool) -> Sim {
+        let mut config = BTreeMap::new();
+        for (name, value) in &[
+            ("general:num_version_config", "1"),
+            ("general:num_version_mb", "34"),
+            ("general:num_version_soft", "2.4.8"),
+            ("general:drone_serial", "SIMULATOR000"),
+            ("general:navdata_demo", "TRUE"),
+            ("general:navdata_options", "1"),
+            ("control:altitude_max", "3000"),
+            ("control:altitude_min", "50"),
+            ("control:euler_angle_max", "0.21"),
+            ("control:control_vz_max", "700"),
+            ("control:control_yaw", "1.75"),
+            ("control:outdoor", "FALSE"),
+            ("control:flight_without_shell", "FALSE"),
+            ("custom:session_id", "00000000"),
+            ("custom:profile_id", "00000000"),
+            ("custom:application_id", "00000000"),
+            ("network:ssid_single_player", "ardrone2_sim"),
+            ("video:video_codec", "129"),
+        ] {
+            config.insert(String::from(*name), String::from(*value));
+        }
+        Sim {
+            verbose,
+            start: Instant::now(),
+            state: ControlState::Landed,
+            emergency: false,
+            last_ref_emergency: false,
+            progressive: false,
+            roll: 0.0,
+            pitch: 0.0,
+            gaz: 0.0,
+            yaw: 0.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            vn: 0.0,
+            ve: 0.0,
+            vz: 0.0,
+            theta: 0.0,
+            phi: 0.0,
+            psi: 0.0,
+            battery: 100.0,
+            bootstrap: true,
+            command_ack: false,
+            last_at_seq: 0,
+            last_command: Instant::now(),
+            config,
+            control_clients: Vec::new(),
+            navdata_client: None,
+            seq_num: 0,
+        }
+    }
+
+    fn config_f32(&self, name: &str, default: f32) -> f32 {
+        self.config.get(name).and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+
+    fn airborne(&self) -> bool {
+        matches!(self.state, ControlState::Flying | ControlState::Hovering
+                 | ControlState::TransTakeoff | ControlState::TransLanding
+                 | ControlState::TransGotofix | ControlState::TransLooping)
+    }
+
+    fn demo_mode(&self) -> bool {
+        self.config.get("general:navdata_demo").map(|v| v == "TRUE").unwrap_or(true)
+    }
+
+    /// Handles every AT command of a datagram
+    fn handle_datagram(&mut self, datagram: &[u8]) {
+        let text = String::from_utf8_lossy(datagram).into_owned();
+        for command in text.split('\r').filter(|c| !c.is_empty()) {
+            if self.verbose {
+                println!("> {}", command);
+            }
+            let parsed = match parse_at_command(command) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            };
+            // A sequence number of 1 starts a new session, older commands
+            // are ignored like on the drone
+            if parsed.seq == 1 {
+                self.last_at_seq = 0;
+            }
+            if parsed.seq <= self.last_at_seq {
+                continue;
+            }
+            self.last_at_seq = parsed.seq;
+            self.last_command = Instant::now();
+            match AtCommand::from_parsed(&parsed) {
+                Ok(command) => self.handle_command(command),
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: AtCommand) {
+        match command {
+            AtCommand::Ref { takeoff, emergency } => self.handle_ref(takeoff, emergency),
+            AtCommand::Pcmd { progressive, roll, pitch, gaz, yaw, .. }
+                | AtCommand::PcmdMag { progressive, roll, pitch, gaz, yaw, .. } => {
+                self.progressive = progressive;
+                self.roll = roll.clamp(-1.0, 1.0);
+                self.pitch = pitch.clamp(-1.0, 1.0);
+                self.gaz = gaz.clamp(-1.0, 1.0);
+                self.yaw = yaw.clamp(-1.0, 1.0);
+            }
+            AtCommand::Ftrim if !self.airborne() => println!("Flat trim done"),
+            AtCommand::Config { key, value } => {
+                println!("Config {} = {}", key, value);
+                if key == "general:navdata_demo" {
+                    self.bootstrap = false;
+                }
+                self.config.insert(key, value);
+                self.command_ack = true;
+            }
+            AtCommand::Ctrl(CtrlMode::Ack) => { self.command_ack = false; }
+            AtCommand::Ctrl(CtrlMode::CfgGet) => { self.send_config(); }
+            AtCommand::Led { animation, frequency, duration } => {
+                println!("LED animation {} at {} Hz for {} s", animation, frequency, duration);
+            }
+            AtCommand::Anim { animation, duration } => {
+                println!("Flight animation {} for {} s", animation, duration);
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_ref(&mut self, takeoff: bool, emergency: bool) {
+        let emergency_edge = emergency && !self.last_ref_emergency;
+        self.last_ref_emergency = emergency;
+        if emergency_edge {
+            if self.emergency {
+                if !self.airborne() {
+                    println!("Emergency reset");
+                    self.emergency = false;
+                    self.state = ControlState::Landed;
+                }
+            } else {
+                println!("Emergency! Motors cut");
+                self.emergency = true;
+                self.state = ControlState::Default;
+            }
+            return;
+        }
+        if self.emergency {
+            return;
+        }
+        if takeoff {
+            if self.state == ControlState::Landed {
+                println!("Taking off");
+                self.state = ControlState::TransTakeoff;
+            }
+        } else if self.airborne() && self.state != ControlState::TransLanding {
+            println!("Landing");
+            self.state = ControlState::TransLanding;
+        }
+    }
+
+    /// Writes the config to every control connection
+    fn send_config(&mut self) {
+        let dump = self.config.iter()
+            .map(|(name, value)| format!("{} = {}\n", name, value))
+            .collect::<String>();
+        self.control_clients.retain_mut(|c| c.write_all(dump.as_bytes()).is_ok());
+    }
+
+    /// Advances the model by dt seconds
+    fn step(&mut self, dt: f32) {
+        if self.last_command.elapsed() > COM_WATCHDOG_TIMEOUT && self.progressive {
+            println!("Connection lost, hovering");
+            self.progressive = false;
+        }
+
+        let max_angle = self.config_f32("control:euler_angle_max", 0.21).to_degrees();
+        let vz_max = self.config_f32("control:control_vz_max", 700.0) / 1000.0;
+        let yaw_max = self.config_f32("control:control_yaw", 1.75).to_degrees();
+        let altitude_max = self.config_f32("control:altitude_max", 3000.0) / 1000.0;
+
+        let (roll, pitch, gaz, yaw) = match self.state {
+            ControlState::Flying | ControlState::Hovering if self.progressive => {
+                (self.roll, self.pitch, self.gaz, self.yaw)
+            }
+            _ => (0.0, 0.0, 0.0, 0.0),
+        };
+        if self.state == ControlState::Flying || self.state == ControlState::Hovering {
+            let moving = roll != 0.0 || pitch != 0.0 || gaz != 0.0 || yaw != 0.0;
+            self.state = if moving { ControlState::Flying } else { ControlState::Hovering };
+        }
+
+        // Attitude follows the commands, the yaw command is a rate
+        self.phi = approach(self.phi, roll * max_angle, ATTITUDE_TAU, dt);
+        self.theta = approach(self.theta, pitch * max_angle, ATTITUDE_TAU, dt);
+        self.psi += yaw * yaw_max * dt;
+        if self.psi > 180.0 {
+            self.psi -= 360.0;
+        } else if self.psi < -180.0 {
+            self.psi += 360.0;
+        }
+
+        // Tilting accelerates the drone, a negative pitch is forward
+        let forward = -G * self.theta.to_radians().tan();
+        let right = G * self.phi.to_radians().tan();
+        let (sin, cos) = self.psi.to_radians().sin_cos();
+        let drag = if self.progressive && self.state == ControlState::Flying { DRAG } else { HOVER_DRAG };
+        if self.airborne() && !self.emergency {
+            self.vn += (forward * cos - right * sin - drag * self.vn) * dt;
+            self.ve += (forward * sin + right * cos - drag * self.ve) * dt;
+        } else {
+            self.vn = 0.0;
+            self.ve = 0.0;
+        }
+
+        let vz_target = match self.state {
+            ControlState::TransTakeoff => TAKEOFF_SPEED,
+            ControlState::TransLanding => -LANDING_SPEED,
+            ControlState::Flying | ControlState::Hovering => gaz * vz_max,
+            _ => 0.0,
+        };
+        if self.emergency && self.z > 0.0 {
+            self.vz -= G * dt;
+        } else {
+            self.vz = approach(self.vz, vz_target, VZ_TAU, dt);
+        }
+
+        self.x += self.vn * dt;
+        self.y += self.ve * dt;
+        self.z = (self.z + self.vz * dt).min(altitude_max);
+
+        if self.state == ControlState::TransTakeoff && self.z >= TAKEOFF_ALTITUDE {
+            println!("Hovering");
+            self.state = ControlState::Hovering;
+        }
+        if self.z <= 0.0 {
+            self.z = 0.0;
+            self.vz = 0.0;
+            if self.state == ControlState::TransLanding || self.state == ControlState::Flying
+                || self.state == ControlState::Hovering {
+                println!("Landed");
+                self.state = ControlState::Landed;
+            }
+        }
+        if self.emergency && self.z == 0.0 {
+            self.phi = 0.0;
+            self.theta = 0.0;
+        }
+
+        let drain = if self.airborne() { 0.05 } else { 0.005 };
+        self.battery = (self.battery - drain * dt).max(0.0);
+        if self.battery < 10.0 && self.airborne() && self.state != ControlState::TransLanding {
+            println!("Battery empty, landing");
+            self.state = ControlState::TransLanding;
+        }
+    }
+
+    fn drone_state(&self) -> u32 {
+        let mut bits = 0;
+        let mut set = |bit: u32, value: bool| if value { bits |= 1 << bit; };
+        set(0, self.airborne());
+        set(6, self.command_ack);
+        set(10, self.demo_mode());
+        set(11, self.bootstrap);
+        set(15, self.battery < 20.0);
+        set(26, true); // navdata thread
+        set(30, self.last_command.elapsed() > COM_WATCHDOG_TIMEOUT);
+        set(31, self.emergency);
+        bits
+    }
+
+    fn control_state_number(&self) -> u32 {
+        match self.state {
+            ControlState::Default => 0,
+            ControlState::Init => 1,
+            ControlState::Landed => 2,
+            ControlState::Flying => 3,
+            ControlState::Hovering => 4,
+            ControlState::Test => 5,
+            ControlState::TransTakeoff => 6,
+            ControlState::TransGotofix => 7,
+            ControlState::TransLanding => 8,
+            ControlState::TransLooping => 9,
+            ControlState::Unknown(s) => s,
+        }
+    }
+
+    /// The next navdata packet: only the header in bootstrap mode, the demo
+    /// block in demo mode, the blocks of general:navdata_options otherwise
+    fn navdata(&mut self) -> Vec<u8> {
+        self.seq_num += 1;
+        let mut snapshot = NavDataSnapshot {
+            header: Header {
+                header: NAVDATA_HEADER,
+                drone_state: DroneState(self.drone_state()),
+                seq_num: self.seq_num,
+                vision_flag: 0,
+            },
+            ..NavDataSnapshot::default()
+        };
+        if self.bootstrap {
+            return NavDataPacket::from_snapshot(&snapshot).to_bytes();
+        }
+
+        let mut options = self.config.get("general:navdata_options")
+            .and_then(|v| v.parse().ok())
+            .map(NavDataOptions::from_bits)
+            .unwrap_or(NavDataOptions::DEMO);
+        if self.demo_mode() {
+            options |= NavDataOptions::DEMO;
+        } else if options == NavDataOptions::DEMO {
+            options = NavDataOptions::all() & !NavDataOptions::GPS;
+        }
+
+        // Speeds in the frame of the drone
+        let (sin, cos) = self.psi.to_radians().sin_cos();
+        let vx = self.vn * cos + self.ve * sin;
+        let vy = -self.vn * sin + self.ve * cos;
+        let elapsed = self.start.elapsed();
+        let motors = if self.airborne() && !self.emergency { 160 } else { 0 };
+
+        snapshot.demo = Some(DemoData {
+            ctrl_state: self.control_state_number() << 16,
+            battery: self.battery.round() as u32,
+            theta: self.theta * 1000.0,
+            phi: self.phi * 1000.0,
+            psi: self.psi * 1000.0,
+            altitude: (self.z * 1000.0) as i32,
+            vx: vx * 1000.0,
+            vy: vy * 1000.0,
+            vz: self.vz * 1000.0,
+            ..DemoData::default()
+        });
+        snapshot.time = Some(Time {
+            time: (elapsed.as_secs() as u32) << 11 | (elapsed.subsec_micros() & 0x7FF),
+        });
+        snapshot.phys_measures = Some(PhysMeasures {
+            phys_accs: [
+                -1000.0 * self.theta.to_radians().sin(),
+                1000.0 * self.phi.to_radians().sin(),
+                -1000.0,
+            ],
+            ..PhysMeasures::default()
+        });
+        snapshot.euler_angles = Some(EulerAngles {
+            theta_a: self.theta * 1000.0,
+            phi_a: self.phi * 1000.0,
+        });
+        snapshot.references = Some(References {
+            ref_theta: (self.pitch * 1000.0) as i32,
+            ref_phi: (self.roll * 1000.0) as i32,
+            ref_psi: (self.psi * 1000.0) as i32,
+            ..References::default()
+        });
+        snapshot.pwm = Some(Pwm {
+            motors: [motors; 4],
+            ..Pwm::default()
+        });
+        snapshot.altitude = Some(Altitude {
+            altitude_vision: (self.z * 1000.0) as i32,
+            altitude_vz: self.vz * 1000.0,
+            altitude_raw: (self.z * 1000.0) as i32,
+            ..Altitude::default()
+        });
+        snapshot.magneto = Some(Magneto {
+            heading_unwrapped: self.psi,
+            heading_gyro_unwrapped: self.psi,
+            heading_fusion_unwrapped: self.psi,
+            magneto_calibration_ok: 1,
+            ..Magneto::default()
+        });
+        snapshot.wifi = Some(Wifi { link_quality: 100 });
+        snapshot.watchdog = Some(Watchdog::default());
+
+        let mut packet = NavDataPacket::from_snapshot(&snapshot);
+        packet.options.retain(|o| options.contains(NavDataOptions::from_id(o.id())));
+        packet.to_bytes()
+    }
+}
+
+fn lock(sim: &Mutex<Sim>) -> std::sync::MutexGuard<'_, Sim> {
+    sim.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn at_thread(socket: UdpSocket, sim: Arc<Mutex<Sim>>) {
+    let mut buff = [0; 4096];
+    loop {
+        match socket.recv(&mut buff) {
+            Ok(len) => lock(&sim).handle_datagram(&buff[..len]),
+            Err(e) => {
+                eprintln!("AT socket: {}", e);
+                thread::sleep(STEP);
+            }
+        }
+    }
+}
+
+fn control_thread(listener: TcpListener, sim: Arc<Mutex<Sim>>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                println!("Control connection from {:?}", stream.peer_addr());
+                lock(&sim).control_clients.push(stream);
+            }
+            Err(e) => eprintln!("Control port: {}", e),
+        }
+    }
+}
+
+/// Runs the model and sends navdata to whoever sent the last wake-up
+fn navdata_loop(socket: UdpSocket, sim: Arc<Mutex<Sim>>) {
+    let mut buff = [0; 64];
+    let mut last_step = Instant::now();
+    let mut last_navdata = Instant::now();
+    loop {
+        thread::sleep(STEP);
+        while let Ok((_, address)) = socket.recv_from(&mut buff) {
+            let mut sim = lock(&sim);
+            if sim.navdata_client != Some(address) {
+                println!("Sending navdata to {}", address);
+                sim.navdata_client = Some(address);
+            }
+        }
+
+        let mut sim = lock(&sim);
+        let now = Instant::now();
+        sim.step((now - last_step).as_secs_f32());
+        last_step = now;
+
+        let period = if sim.demo_mode() && !sim.bootstrap { DEMO_PERIOD } else { STEP };
+        if let Some(address) = sim.navdata_client {
+            if last_navdata.elapsed() >= period {
+                last_navdata = now;
+                let packet = sim.navdata();
+                if let Err(e) = socket.send_to(&packet, address) {
+                    eprintln!("Navdata: {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn run(args: Args) -> std::io::Result<()> {
+    let at_socket = UdpSocket::bind((args.bind.as_str(), args.cmd_port))?;
+    let navdata_socket = UdpSocket::bind((args.bind.as_str(), args.navdata_port))?;
+    navdata_socket.set_nonblocking(true)?;
+    let control = TcpListener::bind((args.bind.as_str(), args.control_port))?;
+    println!("Simulated drone on {}: AT commands on {}, navdata on {}, control on {}",
+             args.bind, args.cmd_port, args.navdata_port, args.control_port);
+
+    let sim = Arc::new(Mutex::new(Sim::new(args.verbose)));
+    let at_sim = sim.clone();
+    thread::spawn(move || at_thread(at_socket, at_sim));
+    let control_sim = sim.clone();
+    thread::spawn(move || control_thread(control, control_sim));
+    navdata_loop(navdata_socket, sim);
+    Ok(())
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(args) {
+        eprintln!("ardrone-sim: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Sends a command with the next sequence number
+    fn send(sim: &mut Sim, command: AtCommand) {
+        let seq = sim.last_at_seq + 1;
+        sim.handle_datagram(command.to_at(seq).as_bytes());
+    }
+
+    /// Runs the model until it reaches the state, for at most 10 s of
+    /// simulated time
+    fn run_until(sim: &mut Sim, state: ControlState) -> bool {
+        let dt = STEP.as_secs_f32();
+        for _ in 0..(10.0 / dt) as usize {
+            sim.step(dt);
+            if sim.state == state {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn decoded_navdata(sim: &mut Sim) -> NavDataSnapshot {
+        parse_navdata(&sim.navdata()).unwrap().snapshot()
+    }
+
+    #[test]
+    fn takeoff_ref_reaches_hovering_and_land_ref_reaches_landed() {
+        let mut sim = Sim::new(false);
+        send(&mut sim, AtCommand::Ref { takeoff: true, emergency: false });
+        assert_eq!(sim.state, ControlState::TransTakeoff);
+        assert!(run_until(&mut sim, ControlState::Hovering));
+        assert!(sim.z >= TAKEOFF_ALTITUDE);
+        assert!(DroneState(sim.drone_state()).flying());
+
+        send(&mut sim, AtCommand::Ref { takeoff: false, emergency: false });
+        assert_eq!(sim.state, ControlState::TransLanding);
+        assert!(run_until(&mut sim, ControlState::Landed));
+        assert_eq!(sim.z, 0.0);
+        assert!(!DroneState(sim.drone_state()).flying());
+    }
+
+    #[test]
+    fn emergency_toggles_on_the_rising_edge_only() {
+        let mut sim = Sim::new(false);
+        send(&mut sim, AtCommand::Ref { takeoff: false, emergency: true });
+        assert!(sim.emergency);
+        assert!(DroneState(sim.drone_state()).emergency());
+
+        // The bit is still set: no edge, no reset
+        send(&mut sim, AtCommand::Ref { takeoff: false, emergency: true });
+        assert!(sim.emergency);
+        // Takeoff is refused in emergency mode
+        send(&mut sim, AtCommand::Ref { takeoff: true, emergency: false });
+        sim.step(STEP.as_secs_f32());
+        assert_eq!(sim.state, ControlState::Default);
+
+        // A new rising edge resets the emergency on the ground
+        send(&mut sim, AtCommand::Ref { takeoff: false, emergency: true });
+        assert!(!sim.emergency);
+        assert_eq!(sim.state, ControlState::Landed);
+    }
+
+    #[test]
+    fn emergency_cuts_the_motors_in_flight() {
+        let mut sim = Sim::new(false);
+        send(&mut sim, AtCommand::Ref { takeoff: true, emergency: false });
+        assert!(run_until(&mut sim, ControlState::Hovering));
+        send(&mut sim, AtCommand::Ref { takeoff: true, emergency: true });
+        assert!(sim.emergency);
+        for _ in 0..400 {
+            sim.step(STEP.as_secs_f32());
+        }
+        assert_eq!(sim.z, 0.0);
+    }
+
+    #[test]
+    fn ctrl_ack_clears_the_ack_bit_of_the_handshake() {
+        let mut sim = Sim::new(false);
+        let state = DroneState(sim.drone_state());
+        assert!(state.navdata_bootstrap());
+        assert!(!state.command_ack());
+        let packet = parse_navdata(&sim.navdata()).unwrap();
+        assert!(packet.options.is_empty());
+
+        send(&mut sim, AtCommand::Config {
+            key: String::from("general:navdata_demo"),
+            value: String::from("TRUE"),
+        });
+        let state = DroneState(sim.drone_state());
+        assert!(!state.navdata_bootstrap());
+        assert!(state.command_ack());
+        assert!(state.navdata_demo());
+
+        send(&mut sim, AtCommand::Ctrl(CtrlMode::Ack));
+        assert!(!DroneState(sim.drone_state()).command_ack());
+    }
+
+    #[test]
+    fn old_sequence_numbers_are_ignored() {
+        let mut sim = Sim::new(false);
+        let takeoff = AtCommand::Ref { takeoff: true, emergency: false };
+        sim.handle_datagram(takeoff.to_at(10).as_bytes());
+        assert_eq!(sim.state, ControlState::TransTakeoff);
+        let land = AtCommand::Ref { takeoff: false, emergency: false };
+        sim.handle_datagram(land.to_at(9).as_bytes());
+        assert_eq!(sim.state, ControlState::TransTakeoff);
+        // Sequence number 1 starts a new session
+        sim.handle_datagram(land.to_at(1).as_bytes());
+        assert_eq!(sim.state, ControlState::TransLanding);
+    }
+
+    #[test]
+    fn demo_mode_sends_the_demo_block() {
+        let mut sim = Sim::new(false);
+        send(&mut sim, AtCommand::Config {
+            key: String::from("general:navdata_demo"),
+            value: String::from("TRUE"),
+        });
+        let snapshot = decoded_navdata(&mut sim);
+        assert_eq!(snapshot.demo.unwrap().control_state(), ControlState::Landed);
+        assert!(snapshot.time.is_none());
+        assert!(snapshot.altitude.is_none());
+    }
+
+    #[test]
+    fn full_mode_sends_the_requested_options() {
+        let mut sim = Sim::new(false);
+        send(&mut sim, AtCommand::Config {
+            key: String::from("general:navdata_demo"),
+            value: String::from("FALSE"),
+        });
+        let options = NavDataOptions::TIME | NavDataOptions::ALTITUDE;
+        send(&mut sim, AtCommand::Config {
+            key: String::from("general:navdata_options"),
+            value: options.bits().to_string(),
+        });
+        let snapshot = decoded_navdata(&mut sim);
+        assert!(!snapshot.header.drone_state.navdata_demo());
+        assert!(snapshot.time.is_some());
+        assert!(snapshot.altitude.is_some());
+        assert!(snapshot.demo.is_none());
+        assert!(snapshot.magneto.is_none());
+    }
+
+    #[test]
+    fn cfg_get_dumps_the_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut sim = Sim::new(false);
+        sim.control_clients.push(listener.accept().unwrap().0);
+
+        send(&mut sim, AtCommand::Ctrl(CtrlMode::CfgGet));
+        drop(sim);
+        let mut dump = String::new();
+        client.read_to_string(&mut dump).unwrap();
+        assert!(dump.contains("general:navdata_demo = TRUE\n"));
+        assert!(dump.contains("general:drone_serial = SIMULATOR000\n"));
+    }
+}
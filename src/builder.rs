@@ -0,0 +1,163 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::Drone;
+use crate::communication::Communication;
+use crate::internal_config::InternalConfig;
+use crate::transport::Transport;
+
+/// Configures a Drone before it is created, for drones which are not at the
+/// default address (e.g. in station mode, or a simulator).
+/// ```no_run
+/// use parrot_ar_drone::*;
+///
+/// // A simulator on this computer, it uses the default ports so the local
+/// // ones have to be different
+/// let mut drone = DroneBuilder::new()
+///     .drone_ip("127.0.0.1")
+///     .local_nav_data_port(15554)
+///     .local_video_port(15555)
+///     .local_cmd_port(15556)
+///     .show_commands(false)
+///     .build();
+/// drone.startup().unwrap();
+/// ```
+pub struct DroneBuilder {
+    communication: Communication,
+    i_config: InternalConfig,
+    transport: Option<Arc<dyn Transport>>,
+}
+
+impl DroneBuilder {
+    /// Returns a builder with the default settings (see Communication::new
+    /// and InternalConfig::new)
+    pub fn new() -> DroneBuilder {
+        DroneBuilder {
+            communication: Communication::new(),
+            i_config: InternalConfig::new(),
+            transport: None,
+        }
+    }
+
+    /// The drones IP (default is 192.168.1.1)
+    pub fn drone_ip(mut self, ip: &str) -> DroneBuilder {
+        self.communication.drone_ip = String::from(ip);
+        self
+    }
+
+    /// UDP port of the drone sending navdata (default 5554)
+    pub fn nav_data_port(mut self, port: u32) -> DroneBuilder {
+        self.communication.nav_data_port = port;
+        self
+    }
+
+    /// UDP port of the drone sending the video (default 5555)
+    pub fn video_port(mut self, port: u32) -> DroneBuilder {
+        self.communication.video_port = port;
+        self
+    }
+
+    /// UDP port of the drone receiving commands (default 5556)
+    pub fn cmd_port(mut self, port: u32) -> DroneBuilder {
+        self.communication.cmd_port = port;
+        self
+    }
+
+    /// TCP port of the drone sending the config (default 5559)
+    pub fn ctl_port(mut self, port: u32) -> DroneBuilder {
+        self.communication.ctl_port = port;
+        self
+    }
+
+    /// TCP port of the drone sending the recording (default 5553)
+    pub fn rec_port(mut self, port: u32) -> DroneBuilder {
+        self.communication.rec_port = port;
+        self
+    }
+
+    /// Local address the UDP sockets are bound to, to choose the interface
+    /// (default 0.0.0.0, every interface)
+    pub fn bind_ip(mut self, ip: &str) -> DroneBuilder {
+        self.communication.bind_ip = String::from(ip);
+        self
+    }
+
+    /// Local UDP port navdata is received on (default 5554)
+    pub fn local_nav_data_port(mut self, port: u32) -> DroneBuilder {
+        self.communication.local_nav_data_port = port;
+        self
+    }
+
+    /// Local UDP port the video is received on (default 5555)
+    pub fn local_video_port(mut self, port: u32) -> DroneBuilder {
+        self.communication.local_video_port = port;
+        self
+    }
+
+    /// Local UDP port commands are sent from (default 5556)
+    pub fn local_cmd_port(mut self, port: u32) -> DroneBuilder {
+        self.communication.local_cmd_port = port;
+        self
+    }
+
+    /// Lets the OS choose free local ports, needed to use several drones
+    /// from one process (see Fleet). The drone sends navdata to the port the
+    /// wake-up datagram came from, so nothing else has to be configured.
+    pub fn ephemeral_ports(self) -> DroneBuilder {
+        self.local_nav_data_port(0)
+            .local_video_port(0)
+            .local_cmd_port(0)
+    }
+
+    /// How long without commands before a keepalive is sent (default 200 ms).
+    /// The drone considers the connection lost after 2 seconds.
+    pub fn keepalive_period(mut self, period: Duration) -> DroneBuilder {
+        self.communication.keepalive_period = period;
+        self
+    }
+
+    /// How often the queued commands are sent, in one datagram (default
+    /// 30 ms). The last movement is sent again every period.
+    pub fn send_period(mut self, period: Duration) -> DroneBuilder {
+        self.communication.send_period = period;
+        self
+    }
+
+    /// How long the connection probe of startup waits for every port
+    /// (default 1 s)
+    pub fn probe_timeout(mut self, timeout: Duration) -> DroneBuilder {
+        self.communication.probe_timeout = timeout;
+        self
+    }
+
+    /// Print every command sent, except keepalives (default true)
+    pub fn show_commands(mut self, show_commands: bool) -> DroneBuilder {
+        self.i_config.show_commands = show_commands;
+        self
+    }
+
+    /// Print additional debug information (default true)
+    pub fn debug(mut self, debug: bool) -> DroneBuilder {
+        self.i_config.debug = debug;
+        self
+    }
+
+    /// Talk to the drone through this transport (e.g. a MemoryTransport in
+    /// tests) instead of opening the sockets in startup. The IP and the
+    /// ports are not used then.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> DroneBuilder {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Returns the Drone, it still has to be started with startup
+    pub fn build(self) -> Drone {
+        Drone::from_parts(self.communication, self.i_config, self.transport)
+    }
+}
+
+impl Default for DroneBuilder {
+    fn default() -> DroneBuilder {
+        DroneBuilder::new()
+    }
+}
@@ -1,7 +1,26 @@
 use std::net::TcpStream;
 use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{time, thread};
-use std::sync::mpsc::{self, TryRecvError, Sender, Receiver};
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender, Receiver};
+
+use crate::at_command::AtCommand;
+use crate::error::DroneError;
+use crate::probe::{self, Reachability, DEFAULT_PROBE_TIMEOUT};
+use crate::transport::Transport;
+
+/// Messages to the command sender thread
+pub(crate) enum SenderMessage {
+    /// Sends the command with the next sequence number
+    Command(AtCommand),
+    /// Stops the thread
+    Exit,
+}
+
+/// Sender side of the queue of the command sender thread
+pub(crate) type CommandSender = Sender<SenderMessage>;
 
 /// Component that is responsible for the communication between the drone and
 /// this API.
@@ -19,199 +38,540 @@ pub struct Communication {
     pub ctl_port: u32,
     /// TCP port (default 5553) from which we get the recording
     pub rec_port: u32,
+    /// Local address the UDP sockets are bound to (default 0.0.0.0, every
+    /// interface)
+    pub bind_ip: String,
+    /// Local UDP port navdata is received on (default 5554). For this and
+    /// the other local ports 0 lets the OS choose a free port, so several
+    /// drones can be used from one process.
+    pub local_nav_data_port: u32,
+    /// Local UDP port the video is received on (default 5555)
+    pub local_video_port: u32,
+    /// Local UDP port commands are sent from (default 5556)
+    pub local_cmd_port: u32,
+    /// A keepalive command is sent when no command was sent for this long
+    /// (default 200 ms)
+    pub keepalive_period: time::Duration,
+    /// How often the queued commands are sent (default 30 ms, as in the
+    /// SDK). The last movement is sent again every period.
+    pub send_period: time::Duration,
+    /// How long the connection probe waits for every port (default 1 s)
+    pub probe_timeout: time::Duration,
     /// The join handle of the command sender thread, set to None when initializing
     connection_thread: Option<thread::JoinHandle<()>>,
+    /// Datagrams the command sender thread could not send
+    send_errors: Arc<AtomicU64>,
     /// Sender to the command sender thread, set to None when initializing
-    command_channel: Option<Sender<(String, Vec<String>)>>
+    command_channel: Option<CommandSender>
 }
 
-fn format_command(
-    command_num: usize,
-    command: String,
-    params: Vec<String>) -> String {
-    let command = params.iter().fold(format!("AT*{}={}", command, command_num), 
-                                     |acc, value| format!("{},{}", acc, value));
-    format!("{}\r", command)
+/// How many times a REF is sent again after the first time, one every send
+/// period, as a lost REF could mean a drone which doesn't land
+const REF_REPEATS: u32 = 5;
+/// The drone reads at most this many bytes of a datagram
+const AT_DATAGRAM_MAX: usize = 1024;
+
+/// The queue of the command sender thread. REF commands skip the queue, the
+/// movement commands replace the one which is still queued.
+struct CommandQueue {
+    transport: Arc<dyn Transport>,
+    echo_commands: bool,
+    seq: u32,
+    queue: VecDeque<AtCommand>,
+    /// The last REF and how many times it is still repeated
+    repeated_ref: Option<(AtCommand, u32)>,
+    /// The movement sent again every send period, None until the first REF
+    /// or PCMD
+    last_move: Option<AtCommand>,
+    last_sent: time::Instant,
+    /// Emergency bit of the last REF sent, the drone only reacts to its
+    /// rising edge
+    last_ref_emergency: bool,
+    /// Counts the datagrams which could not be sent
+    send_errors: Arc<AtomicU64>,
 }
 
-fn communication_thread(socket: UdpSocket, 
-                        receiver: Receiver<(String, Vec<String>)>,
-                        address: String,
-                        echo_commands: bool) {
-    let mut cmd_count = 3;
-    let mut wait_count = 0;
-    loop {
-        match receiver.try_recv() {
-            Ok((cmd_str, params)) => {
-                if cmd_str == "exit" {
-                    break;
-                }
-                let s = format_command(cmd_count, cmd_str, params);
-                if echo_commands {
-                    println!("{}", s);
+fn is_move(command: &AtCommand) -> bool {
+    matches!(command, AtCommand::Pcmd { .. } | AtCommand::PcmdMag { .. })
+}
+
+impl CommandQueue {
+    /// The sequence numbers start at 3, after the commands of start_connection
+    fn new(transport: Arc<dyn Transport>,
+           echo_commands: bool,
+           send_errors: Arc<AtomicU64>) -> CommandQueue {
+        CommandQueue {
+            transport,
+            echo_commands,
+            seq: 3,
+            queue: VecDeque::new(),
+            repeated_ref: None,
+            last_move: None,
+            last_sent: time::Instant::now(),
+            last_ref_emergency: false,
+            send_errors,
+        }
+    }
+
+    /// Adds the command to the datagram, unless the datagram would be too big
+    fn append(&mut self, datagram: &mut String, command: &AtCommand, echo: bool) -> bool {
+        let s = command.to_at(self.seq);
+        if !datagram.is_empty() && datagram.len() + s.len() > AT_DATAGRAM_MAX {
+            return false;
+        }
+        if echo {
+            println!("{}", s);
+        }
+        datagram.push_str(&s);
+        self.seq += 1;
+        if let AtCommand::Ref { emergency, .. } = command {
+            self.last_ref_emergency = *emergency;
+        }
+        true
+    }
+
+    /// A failed send is counted, the next commands are sent all the same
+    fn send(&mut self, datagram: &str) {
+        match self.transport.send_at(datagram.as_bytes()) {
+            Ok(()) => self.last_sent = time::Instant::now(),
+            Err(e) => {
+                self.send_errors.fetch_add(1, Ordering::Relaxed);
+                if self.echo_commands {
+                    println!("Couldn't send AT commands: {}", e);
                 }
-                socket.send_to(s.as_bytes(), &address).unwrap(); 
-                cmd_count += 1;
             }
-            Err(TryRecvError::Disconnected) => {
+        }
+    }
+
+    /// REF commands are sent at once. The movements queued before are
+    /// dropped and the drone hovers until the next movement. If the last
+    /// REF had the emergency bit too, a REF without it is sent first so the
+    /// drone sees the bit rise.
+    fn push(&mut self, command: AtCommand) {
+        if let AtCommand::Ref { takeoff, emergency } = command {
+            let mut datagram = String::new();
+            if emergency && self.last_ref_emergency {
+                let cleared = AtCommand::Ref { takeoff, emergency: false };
+                self.append(&mut datagram, &cleared, self.echo_commands);
+            }
+            self.append(&mut datagram, &command, self.echo_commands);
+            self.send(&datagram);
+            self.repeated_ref = Some((command, REF_REPEATS));
+            self.queue.retain(|c| !is_move(c));
+            self.last_move = Some(AtCommand::Pcmd {
+                progressive: false,
+                combined_yaw: false,
+                roll: 0.0,
+                pitch: 0.0,
+                gaz: 0.0,
+                yaw: 0.0,
+            });
+        } else if is_move(&command) {
+            match self.queue.iter_mut().find(|c| is_move(c)) {
+                Some(queued) => *queued = command,
+                None => self.queue.push_back(command),
+            }
+        } else {
+            self.queue.push_back(command);
+        }
+    }
+
+    /// Sends one datagram with the repeated REF, as many queued commands as
+    /// fit and the last movement if none was queued. Before the first
+    /// movement a keepalive is sent when nothing was sent for
+    /// keepalive_period.
+    fn tick(&mut self, keepalive_period: time::Duration) {
+        let mut datagram = String::new();
+        if let Some((command, repeats)) = self.repeated_ref.take() {
+            self.append(&mut datagram, &command, false);
+            if repeats > 1 {
+                self.repeated_ref = Some((command, repeats - 1));
+            }
+        }
+        let mut moved = false;
+        while let Some(command) = self.queue.pop_front() {
+            if !self.append(&mut datagram, &command, self.echo_commands) {
+                self.queue.push_front(command);
+                break;
+            }
+            if is_move(&command) {
+                self.last_move = Some(command);
+                moved = true;
+            }
+        }
+        if !moved {
+            if let Some(command) = self.last_move.clone() {
+                self.append(&mut datagram, &command, false);
+            }
+        }
+        if datagram.is_empty() && self.last_sent.elapsed() >= keepalive_period {
+            self.append(&mut datagram, &AtCommand::Comwdg, false);
+        }
+        if !datagram.is_empty() {
+            self.send(&datagram);
+        }
+    }
+}
+
+fn communication_thread(transport: Arc<dyn Transport>,
+                        receiver: Receiver<SenderMessage>,
+                        echo_commands: bool,
+                        send_period: time::Duration,
+                        keepalive_period: time::Duration,
+                        send_errors: Arc<AtomicU64>) {
+    let mut queue = CommandQueue::new(transport, echo_commands, send_errors);
+    let mut next_tick = time::Instant::now() + send_period;
+    loop {
+        let timeout = next_tick.saturating_duration_since(time::Instant::now());
+        match receiver.recv_timeout(timeout) {
+            Ok(SenderMessage::Exit) | Err(RecvTimeoutError::Disconnected) => {
                 break;
             }
-            Err(TryRecvError::Empty) => {
-                wait_count += 1;
-                if wait_count == 4 {
-                    let s = format_command(cmd_count, String::from("COMWDG"), Vec::new());
-                    socket.send_to(s.as_bytes(), &address).unwrap(); 
-                    cmd_count += 1;
-                    wait_count = 0;
-                }
+            Ok(SenderMessage::Command(command)) => {
+                queue.push(command);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                queue.tick(keepalive_period);
+                // Don't catch up on ticks missed while the thread was late
+                next_tick = (next_tick + send_period).max(time::Instant::now());
             }
         }
-        thread::sleep(time::Duration::from_millis(50));
     }
 }
 
+/// Binds a UDP socket on the given local address
+pub(crate) fn bind_udp(ip: &str, port: u32) -> Result<UdpSocket, DroneError> {
+    UdpSocket::bind(format!("{}:{}", ip, port))
+        .map_err(|source| DroneError::BindFailed { port, source })
+}
+
 impl Communication {
     /// Returns a Communication struct with default settings, if you didn't do
     /// any fancy shenanigans with the drones settings, this should be enough
     /// for you.
     pub fn new() -> Communication {
-        return Communication {
+        Communication {
             drone_ip: String::from("192.168.1.1"),
             nav_data_port: 5554,
             video_port: 5555,
             cmd_port: 5556,
             ctl_port: 5559,
             rec_port: 5553,
+            bind_ip: String::from("0.0.0.0"),
+            local_nav_data_port: 5554,
+            local_video_port: 5555,
+            local_cmd_port: 5556,
+            keepalive_period: time::Duration::from_millis(200),
+            send_period: time::Duration::from_millis(30),
+            probe_timeout: DEFAULT_PROBE_TIMEOUT,
             connection_thread: None,
+            send_errors: Arc::new(AtomicU64::new(0)),
             command_channel: None
-        };
+        }
     }
-    /// Tries connecting to the drone (may hang on routers which have the
-    /// drones ip (default 192.168.1.1)
+
+    /// Tries connecting to the drone, returns whether anything answered at
+    /// its IP within probe_timeout
     pub fn try_connection(&self) -> bool {
-        let socket = TcpStream::connect(format!("{}:{}", self.drone_ip, 21)); 
+        self.probe().is_reachable()
+    }
 
-        match socket {
-            Ok(_) => {
-                return true;
-            }
+    /// Checks which services of the drone answer (FTP, telnet and the
+    /// control port), waiting at most probe_timeout
+    pub fn probe(&self) -> Reachability {
+        probe::probe(&self.drone_ip, self.ctl_port, self.probe_timeout)
+    }
 
-            Err(_) =>  {
-                return false; 
-            }
+    /// Same as try_connection, but fails unless the control port is open and
+    /// tells which services answered
+    pub fn check_connection(&self) -> Result<Reachability, DroneError> {
+        let reachability = self.probe();
+        if reachability.is_ready() {
+            Ok(reachability)
+        } else {
+            Err(DroneError::NotReady(reachability))
         }
     }
 
-    /// Pushes a command to the send queue, every 50 ms a command is sent, if
-    /// no command is in queue then every 200 ms a keepalive command is sent.
-    pub fn command(&mut self, command: &str, params: Vec<String>) {
-        match &self.command_channel {
-            Some(channel) => {
-                channel.send((String::from(command), params)).unwrap();
-            }
-            None => {}
+    fn connect_tcp(&self, port: u32) -> Result<TcpStream, DroneError> {
+        let address = format!("{}:{}", self.drone_ip, port);
+        TcpStream::connect(&address)
+            .map_err(|source| DroneError::Unreachable { address, source })
+    }
+
+    /// Pushes a command to the send queue. Every send_period the queued
+    /// commands are sent in one datagram, with the last movement (PCMD) if
+    /// none was queued, and before the first movement a keepalive is sent
+    /// every keepalive_period. REF commands (takeoff, land, emergency) are
+    /// sent at once, repeated 5 times and reset the movement to hovering, a
+    /// PCMD replaces the one still in queue so only the latest movement is
+    /// sent.
+    ///
+    /// Does nothing if the connection is not started, returns an error if
+    /// the command sender thread stopped.
+    pub fn command(&mut self, command: AtCommand) -> Result<(), DroneError> {
+        if let Some(channel) = &self.command_channel {
+            channel.send(SenderMessage::Command(command))
+                .map_err(|_| DroneError::ThreadDied("command sender"))?;
         }
+        Ok(())
     }
 
-    /// Same as command, however it takes Vec<&str> and converts is himself
-    pub fn command_str(&mut self, command: &str, params: Vec<&str>) {
-        let string_params = params.iter()
-            .map(|s: &&str| String::from(*s)).collect::<Vec<String>>();
-        self.command(command, string_params);
+    /// How many datagrams of commands could not be sent. The command sender
+    /// thread keeps going after a failed send.
+    pub fn send_errors(&self) -> u64 {
+        self.send_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns a sender to the command queue, so other threads can send
+    /// commands too. None if the connection is not started.
+    pub(crate) fn command_sender(&self) -> Option<CommandSender> {
+        self.command_channel.clone()
     }
 
     /// Initialises the connection with the drone, sends 2 commands which
     /// seem to initialize the drone (taken from ps_drone). Creates a separate
     /// thread to deal with sending these commands and the keepalive sign.
     /// Parameters: echo_commands: Should it print every command sent? (except keepalives)
-    pub fn start_connection(&mut self, echo_commands: &bool) -> Result<(), String> {
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", self.cmd_port)).expect("couldn't bind to address");
-        socket.set_nonblocking(true).unwrap();
-        let address = format!("{}:{}", self.drone_ip, self.cmd_port);
-
-        // Creating a channel to kill the thread
-        let (sender, receiver) = mpsc::channel();
-        self.command_channel = Some(sender);
-
+    ///
+    /// transport: The link the commands are sent on (see UdpTransport)
+    pub fn start_connection(&mut self,
+                            echo_commands: &bool,
+                            transport: Arc<dyn Transport>) -> Result<(), DroneError> {
         // Sending first two commands
         let s = String::from("\r");
-        socket.send_to(s.as_bytes(), &address).unwrap();
+        transport.send_at(s.as_bytes())?;
         thread::sleep(time::Duration::from_millis(10));
-        let s = String::from("AT*PMODE=1,2\rAT*MISC=2,2,20,2000,3000\r");
+        let s = AtCommand::Pmode(2).to_at(1) + &AtCommand::Misc(2, 20, 2000, 3000).to_at(2);
         let e_c = *echo_commands;
+        let keepalive_period = self.keepalive_period;
+        let send_period = self.send_period;
+        let send_errors = self.send_errors.clone();
         if e_c {
             println!("{}", s);
         }
-        socket.send_to(s.as_bytes(), &address).unwrap();
+        transport.send_at(s.as_bytes())?;
+
+        // Creating a channel to kill the thread
+        let (sender, receiver) = mpsc::channel();
+        self.command_channel = Some(sender);
 
         self.connection_thread = Some(thread::spawn(move || {
-            communication_thread(socket,
+            communication_thread(transport,
                                  receiver,
-                                 address,
-                                 e_c);
+                                 e_c,
+                                 send_period,
+                                 keepalive_period,
+                                 send_errors)
         }));
-        return Ok(());
+        Ok(())
     }
 
-    /// Shuts down the communication thread and the connection to the drone
-    pub fn shutdown_connection(&mut self) {
-        let sender = self.command_channel.take().unwrap();
-        sender.send((String::from("exit"), Vec::new())).unwrap();
-        self.connection_thread.take().unwrap().join().unwrap();
+    /// Shuts down the communication thread and the connection to the drone.
+    /// Returns an error if the thread panicked.
+    pub fn shutdown_connection(&mut self) -> Result<(), DroneError> {
+        if let Some(sender) = self.command_channel.take() {
+            // The thread may already be gone, joining tells why
+            let _ = sender.send(SenderMessage::Exit);
+        }
+        match self.connection_thread.take() {
+            Some(handle) => handle.join().map_err(|_| DroneError::ThreadDied("command sender")),
+            None => Ok(()),
+        }
     }
 
-    pub fn get_ctl_tcp_connection(&self) -> Result<TcpStream, String> {
-        let socket = TcpStream::connect(format!("{}:{}", self.drone_ip, self.ctl_port)) ;
-        match socket {
-            Ok(stream) => {
-                return Ok(stream);
-            }
-            Err(error) => {
-                return Err(format!("{}", error));
-            }
-        }
+    pub fn get_ctl_tcp_connection(&self) -> Result<TcpStream, DroneError> {
+        self.connect_tcp(self.ctl_port)
+    }
+
+    /// Returns a UDP socket bound to local_cmd_port which sends to the drones
+    /// cmd_port
+    pub fn get_cmd_udp_connection(&self) -> Result<UdpSocket, DroneError> {
+        let socket = bind_udp(&self.bind_ip, self.local_cmd_port)?;
+        // Only this drone's datagrams are received (and commands go to it)
+        socket.connect(format!("{}:{}", self.drone_ip, self.cmd_port))?;
+        Ok(socket)
     }
 
-    pub fn get_navdata_udp_connection(&self) -> Result<UdpSocket, String> {
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", self.nav_data_port))
-            .expect("couldn't bind to address");
-        socket.set_nonblocking(true).unwrap();
+    pub fn get_navdata_udp_connection(&self) -> Result<UdpSocket, DroneError> {
+        let socket = bind_udp(&self.bind_ip, self.local_nav_data_port)?;
+        socket.set_nonblocking(true)?;
+        socket.connect(format!("{}:{}", self.drone_ip, self.nav_data_port))?;
+        Ok(socket)
+    }
 
-        match socket.connect(format!("{}:{}", self.drone_ip, self.nav_data_port)) {
-            Ok(_) => {
-                Ok(socket)
-            }
-            Err(err) => {
-                Err(format!("{}", err))
-            }
-        }
+    pub fn get_video_udp_connection(&self) -> Result<UdpSocket, DroneError> {
+        let socket = bind_udp(&self.bind_ip, self.local_video_port)?;
+        socket.set_nonblocking(true)?;
+        socket.connect(format!("{}:{}", self.drone_ip, self.video_port))?;
+        Ok(socket)
     }
 
-    pub fn get_video_udp_connection(&self) -> Result<UdpSocket, String> {
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", self.video_port))
-            .expect("couldn't bind to address");
-        socket.set_nonblocking(true).unwrap();
+    pub fn get_record_tcp_connection(&self) -> Result<TcpStream, DroneError> {
+        self.connect_tcp(self.rec_port)
+    }
 
-        match socket.connect(format!("{}:{}", self.drone_ip, self.nav_data_port)) {
-            Ok(_) => {
-                Ok(socket)
-            }
-            Err(err) => {
-                Err(format!("{}", err))
-            }
+}
+
+impl Default for Communication {
+    fn default() -> Communication {
+        Communication::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::at_parser::parse_at_commands;
+    use crate::transport::{MemoryPeer, MemoryTransport};
+
+    const KEEPALIVE: time::Duration = time::Duration::from_millis(200);
+
+    fn memory_queue() -> (CommandQueue, MemoryPeer) {
+        let (transport, peer) = MemoryTransport::new();
+        (CommandQueue::new(Arc::new(transport), false, Arc::default()), peer)
+    }
+
+    /// The commands of every datagram sent since the last call
+    fn sent(peer: &MemoryPeer) -> Vec<Vec<AtCommand>> {
+        peer.at_datagrams().iter()
+            .map(|datagram| parse_at_commands(datagram.as_bytes()).unwrap().iter()
+                 .map(|c| AtCommand::from_parsed(c).unwrap())
+                 .collect())
+            .collect()
+    }
+
+    fn mov(pitch: f32) -> AtCommand {
+        AtCommand::Pcmd { progressive: true, combined_yaw: false, roll: 0.0, pitch, gaz: 0.0, yaw: 0.0 }
+    }
+
+    fn hover() -> AtCommand {
+        AtCommand::Pcmd { progressive: false, combined_yaw: false, roll: 0.0, pitch: 0.0, gaz: 0.0, yaw: 0.0 }
+    }
+
+    fn config(value: usize) -> AtCommand {
+        AtCommand::Config { key: String::from("custom:test"), value: value.to_string() }
+    }
+
+    const LAND: AtCommand = AtCommand::Ref { takeoff: false, emergency: false };
+
+    #[test]
+    fn ref_is_sent_at_once_and_repeated() {
+        let (mut queue, peer) = memory_queue();
+        for i in 0..50 {
+            queue.push(mov(i as f32 / 50.0));
+        }
+        assert!(sent(&peer).is_empty());
+
+        // The REF doesn't wait for the next tick, the moves are dropped
+        queue.push(LAND);
+        assert_eq!(sent(&peer), vec![vec![LAND]]);
+        for _ in 0..REF_REPEATS {
+            queue.tick(KEEPALIVE);
+            assert_eq!(sent(&peer), vec![vec![LAND, hover()]]);
         }
+        queue.tick(KEEPALIVE);
+        assert_eq!(sent(&peer), vec![vec![hover()]]);
     }
 
-    pub fn get_record_tcp_connection(&self) -> Result<TcpStream, String> {
-        let socket = TcpStream::connect(format!("{}:{}", self.drone_ip, self.rec_port)) ;
-        match socket {
-            Ok(stream) => {
-                return Ok(stream);
-            }
-            Err(error) => {
-                return Err(format!("{}", error));
+    #[test]
+    fn queued_moves_are_coalesced() {
+        let (mut queue, peer) = memory_queue();
+        queue.push(config(1));
+        queue.push(mov(0.1));
+        queue.push(AtCommand::Ftrim);
+        queue.push(mov(0.2));
+        queue.push(mov(0.3));
+        queue.tick(KEEPALIVE);
+        assert_eq!(sent(&peer), vec![vec![config(1), mov(0.3), AtCommand::Ftrim]]);
+    }
+
+    #[test]
+    fn sequence_numbers_follow_each_other() {
+        let (mut queue, peer) = memory_queue();
+        queue.push(mov(0.5));
+        queue.push(LAND);
+        queue.tick(KEEPALIVE);
+        let seqs: Vec<u32> = peer.at_datagrams().iter()
+            .flat_map(|d| parse_at_commands(d.as_bytes()).unwrap())
+            .map(|c| c.seq)
+            .collect();
+        assert_eq!(seqs, vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn queued_commands_are_batched_up_to_the_datagram_size() {
+        let (mut queue, peer) = memory_queue();
+        let configs: Vec<AtCommand> = (0..60).map(config).collect();
+        for command in &configs {
+            queue.push(command.clone());
+        }
+        let mut datagrams = Vec::new();
+        while datagrams.len() < 10 {
+            queue.tick(KEEPALIVE);
+            match peer.at_datagrams().as_slice() {
+                [] => break,
+                [datagram] => datagrams.push(datagram.clone()),
+                more => panic!("{} datagrams in one period", more.len()),
             }
         }
+        // Full datagrams: the next command would not fit
+        let longest = configs.iter().map(|c| c.to_at(100).len()).max().unwrap();
+        assert!(datagrams.len() > 1);
+        for (i, datagram) in datagrams.iter().enumerate() {
+            assert!(datagram.len() <= AT_DATAGRAM_MAX);
+            assert!(i == datagrams.len() - 1 || datagram.len() + longest > AT_DATAGRAM_MAX);
+        }
+        let received: Vec<AtCommand> = datagrams.iter()
+            .flat_map(|d| parse_at_commands(d.as_bytes()).unwrap())
+            .map(|c| AtCommand::from_parsed(&c).unwrap())
+            .collect();
+        assert_eq!(received, configs);
     }
 
+    #[test]
+    fn last_move_is_sent_every_period() {
+        let (mut queue, peer) = memory_queue();
+        queue.push(mov(0.5));
+        queue.tick(KEEPALIVE);
+        queue.tick(KEEPALIVE);
+        assert_eq!(sent(&peer), vec![vec![mov(0.5)], vec![mov(0.5)]]);
+
+        // Sent after the other queued commands, unless a new move is queued
+        queue.push(config(1));
+        queue.tick(KEEPALIVE);
+        queue.push(config(2));
+        queue.push(mov(-0.5));
+        queue.tick(KEEPALIVE);
+        assert_eq!(sent(&peer), vec![vec![config(1), mov(0.5)], vec![config(2), mov(-0.5)]]);
+    }
+
+    #[test]
+    fn keepalive_is_sent_after_keepalive_period() {
+        let keepalive = time::Duration::from_millis(50);
+        let (mut queue, peer) = memory_queue();
+        queue.tick(keepalive);
+        assert!(sent(&peer).is_empty());
+
+        thread::sleep(keepalive);
+        queue.tick(keepalive);
+        assert_eq!(sent(&peer), vec![vec![AtCommand::Comwdg]]);
+        queue.tick(keepalive);
+        assert!(sent(&peer).is_empty());
+
+        // Commands sent in between put the keepalive off
+        thread::sleep(keepalive / 2);
+        queue.push(config(1));
+        queue.tick(keepalive);
+        thread::sleep(keepalive / 2);
+        queue.tick(keepalive);
+        assert_eq!(sent(&peer), vec![vec![config(1)]]);
+
+        // The heartbeat move replaces it
+        queue.push(mov(0.5));
+        queue.tick(keepalive);
+        thread::sleep(keepalive);
+        queue.tick(keepalive);
+        assert_eq!(sent(&peer), vec![vec![mov(0.5)], vec![mov(0.5)]]);
+    }
 }
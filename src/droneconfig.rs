@@ -1,60 +1,68 @@
 use std::collections::HashMap;
-use std::io::prelude::*;
-use std::net::TcpStream;
 use std::thread;
+use std::sync::Arc;
 use std::sync::mpsc::{self, TryRecvError, Sender, Receiver};
 
+use crate::error::DroneError;
+use crate::transport::{Transport, TRANSPORT_READ_TIMEOUT, is_timeout};
+
 pub struct DroneConfig {
+    #[allow(dead_code)]
     pub config_data: Vec<(String, String)>,
+    #[allow(dead_code)]
     pub config_sending: bool,
     pub session_id: String,
     pub user_id: String,
     pub application_id: String,
+    #[allow(dead_code)]
     pub send_config_save_mode: bool,
-    command_sender: Option<Sender<String>>,
+    command_sender: Option<Sender<ConfigRequest>>,
     result_receiver: Option<Receiver<Option<String>>>,
     join_handle: Option<thread::JoinHandle<()>>,
 }
 
-fn get_config_thread(op_stream: Option<TcpStream>,
-                     command_receiver: Receiver<String>,
+/// Messages to the config thread
+enum ConfigRequest {
+    /// Asks for the value of a config option
+    Get(String),
+    Exit,
+}
+
+fn get_config_thread(transport: Arc<dyn Transport>,
+                     command_receiver: Receiver<ConfigRequest>,
                      result_sender: Sender<Option<String>>) {
-    let mut stream = op_stream.unwrap();
-    stream.set_nonblocking(true).unwrap();
     let mut options: HashMap<String, String> = HashMap::new();
+    let mut buffer = [0; 4096];
+    // The end of the data read so far which is not a whole line yet
+    let mut partial = Vec::new();
 
     loop {
         match command_receiver.try_recv() {
-            Ok(option_name) => {
-                if option_name == "exit" {
+            Ok(ConfigRequest::Get(option_name)) => {
+                if result_sender.send(options.get(&option_name).cloned()).is_err() {
                     break;
                 }
-                result_sender.send(options.get(&option_name)
-                                   .map(|s_ref| s_ref.clone())).unwrap();
             }
-            Err(TryRecvError::Disconnected) => {
+            Ok(ConfigRequest::Exit) | Err(TryRecvError::Disconnected) => {
                 break;
             }
             Err(TryRecvError::Empty) => {}
         }
-        let mut buffer = String::new();
-        // It's probably a bad idea to use read_to_string because it can hang
-        // if stream has no end!!!
-        let read_result = stream.read_to_string(&mut buffer);
-        match read_result {
-            Ok(_) => {
-                println!("Read anything.");
-                let commands = buffer.split("\n")
-                    .map(|s| String::from(s))
-                    .collect::<Vec<String>>();
-                for s in commands {
-                    let parts = s.split("=")
-                        .map(|s| String::from(s))
-                        .collect::<Vec<String>>();
-                    options.insert(parts[0].clone(), parts[1].clone());
-                }
+        match transport.read_control(&mut buffer) {
+            Ok(len) if len > 0 => { partial.extend_from_slice(&buffer[..len]); }
+            Err(ref e) if is_timeout(e) => { continue; }
+            // The stream is closed, only the queries are answered
+            _ => {
+                thread::sleep(TRANSPORT_READ_TIMEOUT);
+                continue;
+            }
+        }
+        while let Some(end) = partial.iter().position(|b| *b == b'\n') {
+            let line = partial.drain(..=end).collect::<Vec<u8>>();
+            // Lines look like "general:num_version_config = 1"
+            if let Some((name, value)) = String::from_utf8_lossy(&line).split_once('=') {
+                options.insert(String::from(name.trim()), String::from(value.trim()));
             }
-            Err(_) => {}
         }
     }
 }
@@ -62,7 +70,7 @@ fn get_config_thread(op_stream: Option<TcpStream>,
 impl DroneConfig {
     /// Returns a DroneConfig object with default settings
     pub fn new() -> DroneConfig {
-        return DroneConfig {
+        DroneConfig {
             config_data: Vec::new(),
             config_sending: false,
             session_id: String::from("03016321"),
@@ -72,42 +80,47 @@ impl DroneConfig {
             command_sender: None,
             result_receiver: None,
             join_handle: None,
-        };
+        }
     }
 
-    pub fn get_config(&mut self, name: String) -> Option<String> {
-        let cmd_sender = self.command_sender.take().unwrap();
-        cmd_sender.send(name).unwrap();
-        self.command_sender.replace(cmd_sender);
-
-        let res_rec = self.result_receiver.take().unwrap();
-        let recv_res = res_rec.recv();
-        self.result_receiver.replace(res_rec);
-        match recv_res {
-            Ok(res) => { res }
-            _ => { None }
-        }
+    /// Returns the value of a config option, None if the drone didn't send
+    /// it (yet)
+    pub fn get_config(&mut self, name: String) -> Result<Option<String>, DroneError> {
+        let (cmd_sender, res_rec) = match (&self.command_sender, &self.result_receiver) {
+            (Some(cmd_sender), Some(res_rec)) => (cmd_sender, res_rec),
+            _ => return Err(DroneError::NotConnected),
+        };
+        cmd_sender.send(ConfigRequest::Get(name)).map_err(|_| DroneError::ThreadDied("config"))?;
+        res_rec.recv().map_err(|_| DroneError::ThreadDied("config"))
     }
 
-    pub fn get_config_str(&mut self, name: &str) -> Option<String> {
+    pub fn get_config_str(&mut self, name: &str) -> Result<Option<String>, DroneError> {
         self.get_config(String::from(name))
     }
 
-    pub fn start_config_listening_thread(&mut self, tcp_stream: TcpStream) {
+    /// Starts the thread which reads the config from the control stream of
+    /// the transport
+    pub fn start_config_listening_thread(&mut self, transport: Arc<dyn Transport>) {
         let (c_s, c_r) = mpsc::channel();
         let (r_s, r_r) = mpsc::channel();
         self.command_sender = Some(c_s);
         self.result_receiver = Some(r_r);
         self.join_handle = Some(thread::spawn(move || {
-            get_config_thread(Some(tcp_stream),
+            get_config_thread(transport,
             c_r,
             r_s);
         }));
     }
 
-    pub fn stop_config_listening_thread(&mut self) {
-        self.command_sender.take().unwrap().send(String::from("exit")).unwrap();
-        self.result_receiver.take().unwrap();
-        self.join_handle.take().unwrap().join().unwrap();
+    /// Stops the config thread, returns an error if it panicked
+    pub fn stop_config_listening_thread(&mut self) -> Result<(), DroneError> {
+        if let Some(sender) = self.command_sender.take() {
+            let _ = sender.send(ConfigRequest::Exit);
+        }
+        self.result_receiver = None;
+        match self.join_handle.take() {
+            Some(handle) => handle.join().map_err(|_| DroneError::ThreadDied("config")),
+            None => Ok(()),
+        }
     }
 }
@@ -0,0 +1,80 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::navdata::NavDataError;
+use crate::probe::Reachability;
+
+/// Everything that can go wrong while talking to the drone
+#[derive(Debug)]
+pub enum DroneError {
+    /// The drone did not accept a connection on this address
+    Unreachable { address: String, source: io::Error },
+    /// The connection probe failed, tells which services of the drone
+    /// answered
+    NotReady(Reachability),
+    /// A local port could not be bound (e.g. it is used by another program)
+    BindFailed { port: u32, source: io::Error },
+    /// Any other socket or file error
+    Io(io::Error),
+    /// The drone sent something that could not be understood
+    Protocol(String),
+    /// The drone did not answer in time
+    Timeout(String),
+    /// The drone is in emergency mode, the motors are cut (see
+    /// Drone::recover_from_emergency)
+    Emergency,
+    /// The connection (or the component) was not started
+    NotConnected,
+    /// A background thread stopped, it panicked or returned an error
+    ThreadDied(&'static str),
+}
+
+impl fmt::Display for DroneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DroneError::Unreachable { address, source } => {
+                write!(f, "drone unreachable at {}: {}", address, source)
+            }
+            DroneError::NotReady(reachability) => {
+                if reachability.is_reachable() {
+                    write!(f, "drone is not ready ({})", reachability)
+                } else {
+                    write!(f, "drone unreachable ({}), is this computer on its network?", reachability)
+                }
+            }
+            DroneError::BindFailed { port, source } => {
+                write!(f, "couldn't bind to port {}: {}", port, source)
+            }
+            DroneError::Io(e) => write!(f, "{}", e),
+            DroneError::Protocol(message) => write!(f, "protocol error: {}", message),
+            DroneError::Timeout(message) => write!(f, "timed out: {}", message),
+            DroneError::Emergency => write!(f, "the drone is in emergency mode"),
+            DroneError::NotConnected => write!(f, "not connected to the drone"),
+            DroneError::ThreadDied(name) => write!(f, "the {} thread died", name),
+        }
+    }
+}
+
+impl Error for DroneError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DroneError::Unreachable { source, .. } => Some(source),
+            DroneError::BindFailed { source, .. } => Some(source),
+            DroneError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DroneError {
+    fn from(e: io::Error) -> DroneError {
+        DroneError::Io(e)
+    }
+}
+
+impl From<NavDataError> for DroneError {
+    fn from(e: NavDataError) -> DroneError {
+        DroneError::Protocol(e.to_string())
+    }
+}
@@ -0,0 +1,137 @@
+use std::slice;
+
+use crate::{Drone, DroneBuilder, DroneError};
+
+/// A set of drones controlled together. Every drone uses its own local ports
+/// and its sockets only accept datagrams from its IP, so any number of them
+/// can fly from one process.
+/// ```no_run
+/// use parrot_ar_drone::*;
+/// use std::{thread, time};
+///
+/// let mut fleet = Fleet::from_ips(&["192.168.1.10", "192.168.1.11"]);
+/// if let Err(errors) = fleet.startup() {
+///     for (ip, e) in errors {
+///         println!("{}: {}", ip, e);
+///     }
+///     return;
+/// }
+/// fleet.takeoff();
+/// thread::sleep(time::Duration::from_secs(5));
+/// fleet.land();
+/// fleet.shutdown().unwrap();
+/// ```
+#[derive(Default)]
+pub struct Fleet {
+    drones: Vec<Drone>,
+}
+
+impl Fleet {
+    /// Returns an empty fleet
+    pub fn new() -> Fleet {
+        Fleet { drones: Vec::new() }
+    }
+
+    /// Returns a fleet with a drone for every IP, all with ephemeral local
+    /// ports and the other settings at their defaults
+    pub fn from_ips(ips: &[&str]) -> Fleet {
+        let mut fleet = Fleet::new();
+        for ip in ips {
+            fleet.add(DroneBuilder::new().drone_ip(ip).ephemeral_ports().build());
+        }
+        fleet
+    }
+
+    /// Adds a drone, it should not use the same local ports as the others
+    /// (see DroneBuilder::ephemeral_ports)
+    pub fn add(&mut self, drone: Drone) {
+        self.drones.push(drone);
+    }
+
+    /// Removes the drone with this IP and returns it
+    pub fn remove(&mut self, ip: &str) -> Option<Drone> {
+        let index = self.drones.iter().position(|d| d.ip() == ip)?;
+        Some(self.drones.remove(index))
+    }
+
+    pub fn len(&self) -> usize {
+        self.drones.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.drones.is_empty()
+    }
+
+    /// Returns the drone with this IP
+    pub fn drone(&mut self, ip: &str) -> Option<&mut Drone> {
+        self.drones.iter_mut().find(|d| d.ip() == ip)
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Drone> {
+        self.drones.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> slice::IterMut<'_, Drone> {
+        self.drones.iter_mut()
+    }
+
+    /// Calls `f` with every drone, e.g. `fleet.each(|d| d.mov_up(0.5))`
+    pub fn each<F: FnMut(&mut Drone)>(&mut self, f: F) {
+        self.drones.iter_mut().for_each(f);
+    }
+
+    /// Starts every drone. The drones which couldn't be started are returned
+    /// with their IP and error, the others are running.
+    pub fn startup(&mut self) -> Result<(), Vec<(String, DroneError)>> {
+        collect_errors(self.drones.iter_mut().map(|d| (d.ip().to_string(), d.startup())))
+    }
+
+    /// Shuts down every drone, the errors are returned with the IP of the
+    /// drone
+    pub fn shutdown(&mut self) -> Result<(), Vec<(String, DroneError)>> {
+        collect_errors(self.drones.iter_mut().map(|d| (d.ip().to_string(), d.shutdown())))
+    }
+
+    pub fn takeoff(&mut self) {
+        self.each(|d| d.takeoff());
+    }
+
+    pub fn land(&mut self) {
+        self.each(|d| d.land());
+    }
+
+    pub fn hover(&mut self) {
+        self.each(|d| d.hover());
+    }
+
+    /// Sends the emergency/reset command to every drone
+    pub fn reset(&mut self) {
+        self.each(|d| d.reset());
+    }
+
+    /// Cuts the motors of every drone (see Drone::emergency)
+    pub fn emergency(&mut self) {
+        self.each(|d| d.emergency());
+    }
+}
+
+fn collect_errors<I>(results: I) -> Result<(), Vec<(String, DroneError)>>
+    where I: Iterator<Item = (String, Result<(), DroneError>)> {
+    let errors = results
+        .filter_map(|(ip, result)| result.err().map(|e| (ip, e)))
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Fleet {
+    type Item = &'a mut Drone;
+    type IntoIter = slice::IterMut<'a, Drone>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.drones.iter_mut()
+    }
+}
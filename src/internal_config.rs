@@ -25,7 +25,7 @@ pub struct InternalConfig {
 impl InternalConfig {
     /// Returns a default set of internal configs
     pub fn new() -> InternalConfig {
-        return InternalConfig {
+        InternalConfig {
             version: String::from("0.0.1 (2.1.4)"),
             start_time: SystemTime::now(),
             show_commands: true,
@@ -44,3 +44,9 @@ impl InternalConfig {
         }
     }
 }
+
+impl Default for InternalConfig {
+    fn default() -> InternalConfig {
+        InternalConfig::new()
+    }
+}
@@ -1,13 +1,45 @@
 mod communication;
 mod navdata;
+mod navdata_options;
+mod navdata_history;
+mod navdata_recording;
 mod droneconfig;
 mod internal_config;
 mod format;
+mod error;
+mod builder;
+mod fleet;
+mod probe;
+mod transport;
+mod at_parser;
+mod at_command;
 
 pub use navdata::*;
+pub use navdata_options::*;
+pub use navdata_history::*;
+pub use navdata_recording::{NavDataRecorder, NavDataRecording};
 pub use format::*;
 pub use communication::*;
 pub use internal_config::*;
+pub use error::DroneError;
+pub use builder::DroneBuilder;
+pub use fleet::Fleet;
+pub use probe::{probe, probe_port, PortStatus, Reachability, DEFAULT_PROBE_TIMEOUT};
+pub use transport::{Transport, UdpTransport, MemoryTransport, MemoryPeer, TRANSPORT_READ_TIMEOUT};
+pub use at_command::{AtCommand, CtrlMode};
+pub use at_parser::{parse_at_command, parse_at_commands, AtValue, ParsedAtCommand, AtParseError};
+
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long recover_from_emergency waits for the drone after every REF
+const EMERGENCY_RESET_WAIT: Duration = Duration::from_millis(300);
+/// How often the waiting commands look at the navdata
+const STATE_POLL_PERIOD: Duration = Duration::from_millis(100);
+/// How often takeoff_and_wait and land_and_wait send REF again
+const REF_RESEND_PERIOD: Duration = Duration::from_millis(300);
 
 /// First is the codec used for streaming on UDP 5555, second (if exists) is for
 /// recording on TCP 5553.
@@ -25,18 +57,35 @@ pub struct Drone {
     navdata: navdata::NavData,
     config: droneconfig::DroneConfig,
     i_config: internal_config::InternalConfig,
+    /// The links to the drone, opened by startup unless one was given to
+    /// the builder
+    transport: Option<Arc<dyn Transport>>,
 }
 
 impl Drone {
     /// Returns a Drone object with default settings.
     pub fn new() -> Drone {
+        Drone::from_parts(communication::Communication::new(),
+                          internal_config::InternalConfig::new(),
+                          None)
+    }
+
+    /// Returns a DroneBuilder to change the IP, the ports and the other
+    /// settings before creating the Drone.
+    pub fn builder() -> DroneBuilder {
+        DroneBuilder::new()
+    }
+
+    pub(crate) fn from_parts(communication: communication::Communication,
+                             i_config: internal_config::InternalConfig,
+                             transport: Option<Arc<dyn Transport>>) -> Drone {
         Drone {
-            communication: communication::Communication::new(),
+            communication,
             navdata: navdata::NavData::new(),
             config: droneconfig::DroneConfig::new(),
-            i_config: internal_config::InternalConfig::new(),
+            i_config,
+            transport,
         }
-
     }
 
     /// Initializes connection to the drone, starts navdata, control, and config
@@ -53,60 +102,99 @@ impl Drone {
     /// }
     ///
     /// ```
-    pub fn startup(&mut self) -> Result<(), String> {
-        if !self.communication.try_connection() {
-            return Err(String::from("Drone is not online!"));
-        }
-        match self.communication.start_connection(&self.i_config.show_commands) {
-            Ok(()) => { }
-            Err(s) => { return Err(s); }
-        }
-        match self.communication.get_ctl_tcp_connection() {
-            Ok(stream) => { self.config.start_config_listening_thread(stream); }
-            Err(s) => { return Err(s); }
-        }
-
-        match self.communication.get_navdata_udp_connection() {
-            Ok(stream) => { self.navdata.start_navdata_listening_thread(stream, self.i_config.debug)}
-            Err(s) => { return Err(s); }
-        }
+    pub fn startup(&mut self) -> Result<(), DroneError> {
+        let transport = match &self.transport {
+            Some(transport) => transport.clone(),
+            None => {
+                let reachability = self.communication.check_connection()?;
+                if self.i_config.debug {
+                    println!("{}", reachability);
+                }
+                let transport: Arc<dyn Transport> =
+                    Arc::new(UdpTransport::connect(&self.communication)?);
+                self.transport = Some(transport.clone());
+                transport
+            }
+        };
+        self.communication.start_connection(&self.i_config.show_commands, transport.clone())?;
+        self.config.start_config_listening_thread(transport.clone());
+        self.navdata.start_navdata_listening_thread(transport,
+                                                    self.i_config.debug,
+                                                    self.communication.command_sender());
 
         // Is necessary in order to get full NavData back
         self.use_demo_mode(true);
-        self.communication.command_str("CTRL", vec!["5", "0"]);
+        self.communication.command(AtCommand::Ctrl(CtrlMode::Ack))?;
         self.set_config_str("custom:session_id", "-all");
 
-        self.communication.command_str("CTRL", vec!["5", "0"]);
+        self.communication.command(AtCommand::Ctrl(CtrlMode::Ack))?;
         self.update_config();
 
         Ok(())
     }
 
-    /// Shuts down the listening threads, and the control threads.
-    fn shutdown(&mut self) {
-        self.navdata.stop_navdata_listening_thread();
-        self.config.stop_config_listening_thread();
-        self.communication.shutdown_connection();
+    /// Feeds a navdata recording (see record_navdata) to the navdata
+    /// component instead of connecting to a drone, so get_navdata, snapshots
+    /// and subscriptions work offline. Commands are not sent anywhere.
+    ///
+    /// A speed of 1.0 replays the recording in real time, 2.0 twice as fast.
+    /// ```no_run
+    /// use parrot_ar_drone::*;
+    ///
+    /// let mut drone = Drone::new();
+    /// let navdata = drone.subscribe_navdata();
+    /// drone.replay_navdata("flight.navdata", 1.0).unwrap();
+    ///
+    /// for snapshot in navdata {
+    ///     println!("{:?}", snapshot.demo);
+    /// }
+    /// ```
+    pub fn replay_navdata<P: AsRef<Path>>(&mut self, path: P, speed: f32) -> Result<(), DroneError> {
+        let recording = NavDataRecording::open(path)?;
+        self.navdata.start_navdata_replay_thread(recording, speed, self.i_config.debug);
+        Ok(())
+    }
+
+    /// The drones IP
+    pub fn ip(&self) -> &str {
+        &self.communication.drone_ip
+    }
+
+    /// Shuts down the listening threads, and the control threads. Every
+    /// thread is stopped, the first error is returned. Dropping the Drone
+    /// does the same.
+    pub fn shutdown(&mut self) -> Result<(), DroneError> {
+        let navdata = self.navdata.stop_navdata_listening_thread();
+        let config = self.config.stop_config_listening_thread();
+        let communication = self.communication.shutdown_connection();
+        navdata.and(config).and(communication)
+    }
+
+    /// Pushes a command to the send queue. The command methods don't return
+    /// errors: one only happens when the command sender thread stopped, and
+    /// then it is printed in debug mode.
+    fn command(&mut self, command: AtCommand) {
+        let name = command.name();
+        if let Err(e) = self.communication.command(command) {
+            if self.i_config.debug {
+                println!("Couldn't send {}: {}", name, e);
+            }
+        }
     }
 
     /// Tells the drone that it is horizontal (parallel to the ground).
     /// 
     /// Do this only when the drone is on the ground!
     pub fn trim(&mut self) {
-        self.communication.command("FTRIM", Vec::new());
+        self.command(AtCommand::Ftrim);
     }
 
     pub fn mtrim(&mut self) {
-        self.communication.command("CALIB", vec![String::from("0")]);
+        self.command(AtCommand::Calib(0));
     }
 
     pub fn mantrim(&mut self, theta: f32, phi: f32, yaw: f32) {
-        self.communication.command(
-            "MTRIM", 
-            vec![format_float(theta),
-            format_float(phi),
-            format_float(yaw)]
-        );
+        self.command(AtCommand::Mtrim { theta, phi, yaw });
     }
 
     /// The most basic move command.
@@ -136,14 +224,14 @@ impl Drone {
             t_l_r = turn_left_right / turn_left_right.abs();
         }
 
-        self.communication.command("PCMD",
-                                   vec![
-                                   format_int(3),
-                                   format_float(l_r),
-                                   format_float(-b_f),
-                                   format_float(d_u),
-                                   format_float(t_l_r)
-                                   ]);
+        self.command(AtCommand::Pcmd {
+            progressive: true,
+            combined_yaw: true,
+            roll: l_r,
+            pitch: -b_f,
+            gaz: d_u,
+            yaw: t_l_r,
+        });
     }
 
     /// Move relative to the controller
@@ -173,16 +261,17 @@ impl Drone {
             n_ta_a = north_ta_accuracy / north_ta_accuracy.abs();
         }
 
-        self.communication.command("PCMD_MAG",
-                                   vec![
-                                   format_int(1),
-                                   format_float(l_r),
-                                   format_float(-b_f),
-                                   format_float(d_u),
-                                   format_float(t_l_r),
-                                   format_float(e_w),
-                                   format_float(n_ta_a)
-                                   ]);
+        self.command(AtCommand::PcmdMag {
+            progressive: true,
+            combined_yaw: false,
+            absolute_control: false,
+            roll: l_r,
+            pitch: -b_f,
+            gaz: d_u,
+            yaw: t_l_r,
+            psi: e_w,
+            psi_accuracy: n_ta_a,
+        });
     }
 
     /// Stops all movement and turns
@@ -296,10 +385,10 @@ impl Drone {
     ///
     /// Initializes connection to the drone, starts navdata, control, and config
     /// threads. Sends basic commands to the drone to initialize it.
-    /// ```
+    /// ```no_run
     /// use parrot_ar_drone::*;
-    /// use time::Duration;
-    /// use thread;
+    /// use std::time::Duration;
+    /// use std::thread;
     ///
     /// fn main() {
     ///     let mut drone = Drone::new();
@@ -307,21 +396,21 @@ impl Drone {
     ///     drone.startup();
     ///     drone.takeoff();
     ///
-    ///     thread::sleep(Duration::from_secs(5))
+    ///     thread::sleep(Duration::from_secs(5));
     ///
     ///     drone.mov_forward(0.5);
-    ///     thread::sleep(Duration::from_secs(2))
+    ///     thread::sleep(Duration::from_secs(2));
     ///     drone.hover();
     ///
-    ///     thread::sleep(Duration::from_secs(5))
+    ///     thread::sleep(Duration::from_secs(5));
     ///
     ///     drone.land();
-    ///     drone.shutdown();
+    ///     drone.shutdown().unwrap();
     /// }
     ///
     /// ```
     pub fn takeoff(&mut self) {
-        self.communication.command("REF", vec![String::from("290718208")]);
+        self.command(AtCommand::Ref { takeoff: true, emergency: false });
     }
 
     /// Makes the drone land
@@ -330,7 +419,76 @@ impl Drone {
     ///
     /// 290717696=10001010101000000000000000000
     pub fn land(&mut self) {
-        self.communication.command("REF", vec![String::from("290717696")]);
+        self.command(AtCommand::Ref { takeoff: false, emergency: false });
+    }
+
+    /// Takes off and waits until the navdata shows the drone flying or
+    /// hovering, sending the takeoff REF again while it doesn't. Returns an
+    /// Emergency error if the drone is in emergency mode and a Timeout error
+    /// if it is not flying after `timeout` or the navdata link is lost.
+    /// ```no_run
+    /// use parrot_ar_drone::*;
+    /// use std::time::Duration;
+    ///
+    /// let mut drone = Drone::new();
+    /// drone.startup().unwrap();
+    /// drone.takeoff_and_wait(Duration::from_secs(10)).unwrap();
+    /// drone.mov_up(0.5);
+    /// // ...
+    /// drone.land_and_wait(Duration::from_secs(10)).unwrap();
+    /// ```
+    pub fn takeoff_and_wait(&mut self, timeout: Duration) -> Result<(), DroneError> {
+        self.ref_until(true, timeout, |state| {
+            state == ControlState::Flying || state == ControlState::Hovering
+        })
+    }
+
+    /// Lands and waits until the navdata shows the drone landed, sending the
+    /// landing REF again while it doesn't. Returns an Emergency error if the
+    /// drone goes into emergency mode and a Timeout error if it has not
+    /// landed after `timeout` or the navdata link is lost.
+    pub fn land_and_wait(&mut self, timeout: Duration) -> Result<(), DroneError> {
+        self.ref_until(false, timeout, |state| state == ControlState::Landed)
+    }
+
+    /// Sends REF every REF_RESEND_PERIOD until `done` returns true for the
+    /// control state of a packet received after the first REF
+    fn ref_until<F>(&mut self, takeoff: bool, timeout: Duration, done: F) -> Result<(), DroneError>
+        where F: Fn(ControlState) -> bool {
+        let what = if takeoff { "takeoff" } else { "landing" };
+        let deadline = Instant::now() + timeout;
+        let reader = self.navdata.reader();
+        let mut first_sent: Option<Instant> = None;
+        let mut last_sent: Option<Instant> = None;
+        loop {
+            if reader.no_navdata() {
+                return Err(DroneError::Timeout(format!("navdata link lost during the {}", what)));
+            }
+            // The stats first, so the snapshot is at least as new as last_packet
+            let last_packet = reader.stats().last_packet;
+            if let Some(snapshot) = reader.snapshot() {
+                if snapshot.header.drone_state.emergency() {
+                    return Err(DroneError::Emergency);
+                }
+                let fresh = match (first_sent, last_packet) {
+                    (Some(sent), Some(received)) => received > sent,
+                    _ => false,
+                };
+                if fresh && snapshot.demo.map(|d| done(d.control_state())).unwrap_or(false) {
+                    return Ok(());
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(DroneError::Timeout(format!("{} not confirmed by the navdata", what)));
+            }
+            if last_sent.map(|t| now - t >= REF_RESEND_PERIOD).unwrap_or(true) {
+                self.command(AtCommand::Ref { takeoff, emergency: false });
+                first_sent.get_or_insert_with(Instant::now);
+                last_sent = Some(now);
+            }
+            thread::sleep(STATE_POLL_PERIOD.min(deadline - now));
+        }
     }
 
     /// Resets the drone in case the last landing was crashlanding.
@@ -339,25 +497,81 @@ impl Drone {
     ///
     /// 290717952=10001010101000000000100000000
     pub fn reset(&mut self) {
-        self.communication.command("REF", vec![String::from("290717952")]);
+        self.command(AtCommand::Ref { takeoff: false, emergency: true });
+    }
+
+    /// Cuts the motors at once, the drone falls! The drone only toggles its
+    /// emergency mode when the emergency bit of REF goes from 0 to 1, so
+    /// nothing is sent if the navdata says it already is in emergency mode
+    /// (the REF would take it out). If the last REF had the bit already
+    /// (e.g. after reset), a REF without it is sent first.
+    pub fn emergency(&mut self) {
+        if self.drone_state().map(|s| s.emergency()) != Some(true) {
+            self.command(AtCommand::Ref { takeoff: false, emergency: true });
+        }
+    }
+
+    /// Takes the drone out of emergency mode (after emergency, a crash or a
+    /// motor problem): sends REF with the emergency bit until the navdata
+    /// shows the emergency bit cleared. Only the rising edge of the bit
+    /// counts, so a landing REF clears it between the tries and after the
+    /// last one. Does nothing if the drone is not in emergency mode.
+    /// Returns a Timeout error if the drone still is in
+    /// emergency mode (or no navdata came) after `timeout`.
+    /// ```no_run
+    /// use parrot_ar_drone::*;
+    /// use std::time::Duration;
+    ///
+    /// let mut drone = Drone::new();
+    /// drone.startup().unwrap();
+    /// drone.recover_from_emergency(Duration::from_secs(5)).unwrap();
+    /// drone.takeoff();
+    /// ```
+    pub fn recover_from_emergency(&mut self, timeout: Duration) -> Result<(), DroneError> {
+        let deadline = Instant::now() + timeout;
+        let mut sent = false;
+        loop {
+            match self.drone_state() {
+                Some(state) if !state.emergency() => {
+                    if sent {
+                        self.land();
+                    }
+                    return Ok(());
+                }
+                Some(_) if Instant::now() >= deadline => {
+                    return Err(DroneError::Timeout(String::from("the drone stayed in emergency mode")));
+                }
+                None if Instant::now() >= deadline => {
+                    return Err(DroneError::Timeout(String::from("no navdata to see the emergency state")));
+                }
+                None => {
+                    thread::sleep(STATE_POLL_PERIOD);
+                    continue;
+                }
+                Some(_) => {}
+            }
+            self.command(AtCommand::Ref { takeoff: false, emergency: true });
+            sent = true;
+            thread::sleep(EMERGENCY_RESET_WAIT);
+            if self.drone_state().map(|s| s.emergency()) == Some(true) {
+                // Clears the bit, so the next try is seen as a new toggle
+                self.land();
+                thread::sleep(STATE_POLL_PERIOD);
+            }
+        }
     }
 
     /// Do a preset led animation (anim < 21; duration in seconds)
     pub fn led(&mut self, anim: usize, frequency: f32, duration: i32) {
         if anim < 21 && frequency > 0.0 && duration > 0 {
-            self.communication.command("LED", vec![
-                                       format_int(anim as i32),
-                                       format_float(frequency),
-                                       format_int(duration)]);
+            self.command(AtCommand::Led { animation: anim as i32, frequency, duration });
         }
     }
 
     /// Execute a preset movement (anim < 20; duration in seconds)
     pub fn anim(&mut self, anim: usize, duration: i32) {
         if anim < 20 && duration > 0 {
-            self.communication.command("ANIM", vec![
-                                       format_int(anim as i32),
-                                       format_int(duration)]);
+            self.command(AtCommand::Anim { animation: anim as i32, duration });
         }
     }
 
@@ -384,21 +598,17 @@ impl Drone {
             rr = 1023;
         }
 
-        self.communication.command("PWM", vec![
-                                   format_int(fl as i32),
-                                   format_int(fr as i32),
-                                   format_int(rl as i32),
-                                   format_int(rr as i32)
-        ])
+        self.command(AtCommand::Pwm {
+            front_left: fl as i32,
+            front_right: fr as i32,
+            rear_left: rl as i32,
+            rear_right: rr as i32,
+        })
     }
 
     /// This makes the drone fly around and follow 2D tags detected by it's camera
     pub fn aflight(&mut self, flag: bool) {
-        if flag {
-            self.communication.command_str("AFLIGHT", vec!["1"]);
-        } else {
-            self.communication.command_str("AFLIGHT", vec!["0"]);
-        }
+        self.command(AtCommand::Aflight(flag));
     }
 
     /// Set the default seed of the drone that will be used in the move functions.
@@ -414,8 +624,8 @@ impl Drone {
 
     /// Requests an updated config from the drone
     pub fn update_config(&mut self) {
-        self.communication.command_str("CTRL", vec!["5", "0"]);
-        self.communication.command_str("CTRL", vec!["4", "0"]);
+        self.command(AtCommand::Ctrl(CtrlMode::Ack));
+        self.command(AtCommand::Ctrl(CtrlMode::CfgGet));
     }
 
     /// This function doesn't guarantee that the config read is up to date!
@@ -423,43 +633,43 @@ impl Drone {
     /// To be sure please use the update_config function before this and
     ///
     /// wait a little, to give time to the config thread to process the changes
-    pub fn get_offline_config(&mut self, config_name: &str) -> Option<String> {
+    pub fn get_offline_config(&mut self, config_name: &str) -> Result<Option<String>, DroneError> {
         self.config.get_config_str(config_name)
     }
 
     pub fn send_config_ids(&mut self) {
-        self.communication.command("CONFIG_IDS",
-                                   vec![
-                                   format_string(self.config.session_id.clone()),
-                                   format_string(self.config.user_id.clone()),
-                                   format_string(self.config.application_id.clone()),
-                                   ]);
+        let ids = AtCommand::config_ids(&self.config.session_id,
+                                        &self.config.user_id,
+                                        &self.config.application_id);
+        self.checked_command(ids);
     }
 
     /// This function sends a config to the drone, however it does not check if
     ///
-    /// the drone has gotten the command or not.
+    /// the drone has gotten the command or not. Names and values containing
+    /// `"`, `,` or `\r` are not sent.
     pub fn set_config(&mut self, config_name: &str, config_value: String) {
-        // self.send_config_ids();
-        self.communication.command("CONFIG",
-                                   vec![
-                                   format!("\"{}\"", config_name),
-                                   format!("\"{}\"", config_value)
-                                   ]);
+        self.set_config_str(config_name, &config_value);
     }
 
     /// Same as set_config but this uses &str for config_value
     pub fn set_config_str(&mut self, config_name: &str, config_value: &str) {
         // self.send_config_ids();
-        self.communication.command("CONFIG",
-                                   vec![
-                                   format!("\"{}\"", config_name),
-                                   format!("\"{}\"", config_value)
-                                   ]);
+        self.checked_command(AtCommand::config(config_name, config_value));
+    }
+
+    fn checked_command(&mut self, command: Result<AtCommand, AtParseError>) {
+        match command {
+            Ok(command) => self.command(command),
+            Err(e) => if self.i_config.debug {
+                println!("Couldn't send command: {}", e);
+            },
+        }
     }
 
     /// Enters the drone into demo mode
     pub fn use_demo_mode(&mut self, value: bool) {
+        self.navdata.reader().set_demo_mode(value);
         if value {
             self.set_config_str("general:navdata_demo", "TRUE");
         } else {
@@ -467,26 +677,40 @@ impl Drone {
         }
     }
 
+    /// Tells the drone which navdata option blocks to send
+    /// (`general:navdata_options`). The navdata component then counts the
+    /// packets which lack any of them in navdata_stats.
+    ///
+    /// In demo mode the drone sends 15 packets per second, otherwise 200.
+    /// ```no_run
+    /// use parrot_ar_drone::*;
+    ///
+    /// let mut drone = Drone::new();
+    /// drone.startup().unwrap();
+    /// drone.set_navdata_options(NavDataOptions::DEMO
+    ///                           | NavDataOptions::VISION_DETECT
+    ///                           | NavDataOptions::MAGNETO
+    ///                           | NavDataOptions::PRESSURE_RAW);
+    /// ```
+    pub fn set_navdata_options(&mut self, options: NavDataOptions) {
+        self.set_config("general:navdata_options", format!("{}", options.bits()));
+        self.navdata.reader().set_expected_options(options);
+    }
+
+    /// The option blocks requested with set_navdata_options
+    pub fn navdata_options(&self) -> NavDataOptions {
+        self.navdata.reader().expected_options()
+    }
+
     /// Sets the codec that will be used by the drone for streaming and recording.
     pub fn set_video_codec(&mut self, codec: VideoCodec) {
-        let s;
-        match codec { 
-            VideoCodec::MP4_360p => {
-                s = "128";
-            }
-            VideoCodec::H264_360p => {
-                s = "129";
-            }
-            VideoCodec::H264_720p => {
-                s = "131";
-            }
-            VideoCodec::MP4_360pH264_720p => {
-                s = "130";
-            }
-            VideoCodec::MP4_360pH264_360p => {
-                s = "136";
-            }
-        }
+        let s = match codec { 
+            VideoCodec::MP4_360p => "128",
+            VideoCodec::H264_360p => "129",
+            VideoCodec::H264_720p => "131",
+            VideoCodec::MP4_360pH264_720p => "130",
+            VideoCodec::MP4_360pH264_360p => "136",
+        };
 
         self.set_config_str("video:video_codec", s);
     }
@@ -532,12 +756,7 @@ impl Drone {
 
     /// Set the FPS for the video on (UDP 5555)
     pub fn set_video_bitrate(&mut self, bitrate: u32) {
-        let mut real_bitrate = bitrate;
-        if bitrate > 20000 {
-            real_bitrate = 20000;
-        } else if bitrate < 250 {
-            real_bitrate = 250;
-        }
+        let real_bitrate = bitrate.clamp(250, 20000);
         self.set_config("video:bitrate", format!("{}", real_bitrate));
     }
 
@@ -551,14 +770,491 @@ impl Drone {
         self.set_config_str("video:video_channel", "1");
     }
 
-    /// Get Navdata from the drone (currently only supports DEMO mode)
+    /// Get Navdata from the drone. Demo values are prefixed with `demo_`, the
+    /// values of the other option blocks (only sent when demo mode is off)
+    /// are prefixed with the name of the block, e.g. `magneto_mx` or
+    /// `vision_detect_nb_detected`.
     pub fn get_navdata(&mut self, name: &str) -> Option<navdata::NavDataValue> {
         self.navdata.get_navdata_str(name)
     }
+
+    /// Returns every value of the last navdata packet received from the drone,
+    /// or None if no packet was received yet.
+    /// ```no_run
+    /// use parrot_ar_drone::*;
+    ///
+    /// let mut drone = Drone::new();
+    /// drone.startup().unwrap();
+    ///
+    /// if let Some(demo) = drone.navdata_snapshot().and_then(|s| s.demo) {
+    ///     println!("Battery: {}%, altitude: {} mm", demo.battery, demo.altitude);
+    /// }
+    /// ```
+    pub fn navdata_snapshot(&self) -> Option<NavDataSnapshot> {
+        self.navdata.get_snapshot()
+    }
+
+    /// Returns a receiver which gets every new navdata packet as soon as it is
+    /// decoded (15 packets per second in demo mode, 200 otherwise). It can be
+    /// called before startup too.
+    /// ```no_run
+    /// use parrot_ar_drone::*;
+    ///
+    /// let mut drone = Drone::new();
+    /// let navdata = drone.subscribe_navdata();
+    /// drone.startup().unwrap();
+    ///
+    /// for snapshot in navdata {
+    ///     if let Some(demo) = snapshot.demo {
+    ///         println!("Altitude: {} mm", demo.altitude);
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe_navdata(&mut self) -> std::sync::mpsc::Receiver<NavDataSnapshot> {
+        self.navdata.subscribe()
+    }
+
+    /// Returns a handle which reads the latest navdata without the Drone, it
+    /// can be cloned and moved to other threads.
+    pub fn navdata_reader(&self) -> NavDataReader {
+        self.navdata.reader()
+    }
+
+    /// Returns a copy of the last navdata packets with the time they were
+    /// received, to query values over a time window. with_navdata_history
+    /// does the same without copying the packets.
+    pub fn navdata_history(&self) -> NavDataHistory {
+        self.navdata.reader().history()
+    }
+
+    /// Calls `f` with the last navdata packets and the time they were
+    /// received, to query values over a time window. The navdata thread
+    /// waits for `f` before adding a packet, so keep it short.
+    /// ```no_run
+    /// use parrot_ar_drone::*;
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let mut drone = Drone::new();
+    /// drone.startup().unwrap();
+    /// let takeoff = Instant::now();
+    /// drone.takeoff();
+    ///
+    /// // ...
+    ///
+    /// let altitudes = drone.with_navdata_history(|history| {
+    ///     history.values_in_last(Duration::from_secs(5), |s| s.demo.map(|d| d.altitude))
+    /// });
+    /// let mean_vx = drone.with_navdata_history(|history| {
+    ///     history.mean_since(takeoff, |s| s.demo.map(|d| d.vx))
+    /// });
+    /// ```
+    pub fn with_navdata_history<R, F: FnOnce(&NavDataHistory) -> R>(&self, f: F) -> R {
+        self.navdata.reader().with_history(f)
+    }
+
+    /// Sets how many navdata packets are kept in the history (default 1000).
+    pub fn set_navdata_history_size(&mut self, size: usize) {
+        self.navdata.reader().set_history_size(size);
+    }
+
+    /// Registers a callback which is called with every new navdata packet.
+    ///
+    /// The callback runs on the navdata thread, so it should return quickly.
+    pub fn on_navdata<F>(&mut self, callback: F) -> Result<(), DroneError>
+        where F: FnMut(&NavDataSnapshot) + Send + 'static {
+        self.navdata.on_navdata(callback)
+    }
+
+    /// Returns a receiver which gets an event when the navdata link is lost
+    /// (no valid packet for the link timeout, 500 ms by default), when it is
+    /// restored, and when a packet is dropped. While the link is lost the
+    /// navdata component keeps sending the wake-up datagram to the drone.
+    /// ```no_run
+    /// use parrot_ar_drone::*;
+    ///
+    /// let mut drone = Drone::new();
+    /// let events = drone.subscribe_navdata_events();
+    /// drone.startup().unwrap();
+    ///
+    /// for event in events {
+    ///     if event == NavDataEvent::LinkLost {
+    ///         println!("Navdata link lost!");
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe_navdata_events(&mut self) -> std::sync::mpsc::Receiver<NavDataEvent> {
+        self.navdata.subscribe_events()
+    }
+
+    /// Whether the navdata link is lost (see subscribe_navdata_events)
+    pub fn no_navdata(&self) -> bool {
+        self.navdata.no_navdata()
+    }
+
+    /// Sets how long to wait for a valid navdata packet before the link is
+    /// considered lost
+    pub fn set_navdata_timeout(&mut self, timeout: std::time::Duration) {
+        self.navdata.reader().set_link_timeout(timeout);
+    }
+
+    /// Writes every navdata datagram received from now on to the file, with
+    /// the time it was received. It can be called before startup to record
+    /// the whole flight. The file can be played back with replay_navdata.
+    pub fn record_navdata<P: AsRef<Path>>(&mut self, path: P) -> Result<(), DroneError> {
+        self.navdata.start_recording(path)
+    }
+
+    /// Stops the recording started by record_navdata
+    pub fn stop_navdata_recording(&mut self) -> Result<(), DroneError> {
+        self.navdata.stop_recording()
+    }
+
+    /// Returns the navdata link quality of the current session: packets
+    /// received, lost and out of order, checksum failures, packets lacking
+    /// option blocks requested with set_navdata_options, the jitter and the
+    /// effective rate.
+    /// ```no_run
+    /// use parrot_ar_drone::*;
+    ///
+    /// let mut drone = Drone::new();
+    /// drone.startup().unwrap();
+    /// let stats = drone.navdata_stats();
+    /// println!("{:.1} packets/s, {:.1}% lost, jitter {:?}",
+    ///          stats.rate(), stats.loss_ratio() * 100.0, stats.jitter);
+    /// ```
+    pub fn navdata_stats(&self) -> NavDataStats {
+        self.navdata.get_stats()
+    }
+
+    /// How many datagrams of commands could not be sent, the commands after
+    /// a failed send are sent all the same
+    pub fn command_send_errors(&self) -> u64 {
+        self.communication.send_errors()
+    }
+
+    /// Returns the state of the drone from the header of the last navdata
+    /// packet, or None if no packet was received yet.
+    pub fn drone_state(&self) -> Option<DroneState> {
+        self.navdata_snapshot().map(|s| s.header.drone_state)
+    }
+}
+
+impl Default for Drone {
+    fn default() -> Drone {
+        Drone::new()
+    }
 }
 
 impl Drop for Drone {
     fn drop(&mut self) {
-        self.shutdown();
+        if let Err(e) = self.shutdown() {
+            if self.i_config.debug {
+                println!("Error while shutting down: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver, TryRecvError};
+    use std::thread::JoinHandle;
+
+    const EMERGENCY_BIT: u32 = 1 << 31;
+    /// REF arguments of land(), emergency() and takeoff()
+    const REF_LAND: i32 = 290717696;
+    const REF_EMERGENCY: i32 = 290717952;
+    const REF_TAKEOFF: i32 = 290718208;
+
+    /// A navdata packet with the drone state bits and the major control
+    /// state of the demo block
+    fn navdata(seq_num: u32, drone_state: u32, control_state: u32) -> Vec<u8> {
+        let snapshot = NavDataSnapshot {
+            header: Header {
+                header: NAVDATA_HEADER,
+                drone_state: DroneState(drone_state),
+                seq_num,
+                vision_flag: 0,
+            },
+            demo: Some(DemoData { ctrl_state: control_state << 16, ..DemoData::default() }),
+            ..NavDataSnapshot::default()
+        };
+        NavDataPacket::from_snapshot(&snapshot).to_bytes()
+    }
+
+    fn memory_drone() -> (Drone, MemoryPeer) {
+        let (transport, peer) = MemoryTransport::new();
+        let mut drone = DroneBuilder::new()
+            .transport(Arc::new(transport))
+            .show_commands(false)
+            .debug(false)
+            .build();
+        drone.startup().unwrap();
+        (drone, peer)
+    }
+
+    /// Waits (at most 2 seconds) until the navdata state matches `predicate`
+    fn wait_for_state<F: Fn(DroneState) -> bool>(drone: &Drone, predicate: F) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !drone.drone_state().map(&predicate).unwrap_or(false) {
+            assert!(Instant::now() < deadline, "navdata state not reached");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Plays the drone until `stop` is signalled: it sends navdata every
+    /// 20 ms, flies while the REF takeoff bit is set and toggles its
+    /// emergency mode on the rising edge of the REF emergency bit, except
+    /// for the first `ignored_edges` ones. Returns the REF arguments
+    /// received, without the repeats.
+    fn fake_drone(peer: MemoryPeer,
+                  mut emergency: bool,
+                  mut ignored_edges: usize,
+                  stop: Receiver<()>) -> JoinHandle<Vec<i32>> {
+        thread::spawn(move || {
+            let mut refs: Vec<i32> = Vec::new();
+            let mut last_bit = false;
+            let mut flying = false;
+            let mut seq_num = 1;
+            while let Err(TryRecvError::Empty) = stop.try_recv() {
+                if let Some(datagram) = peer.recv_at(Duration::from_millis(20)) {
+                    for command in parse_at_commands(datagram.as_bytes()).unwrap() {
+                        let arg = match command.int(0) {
+                            Some(arg) if command.name == "REF" => arg,
+                            _ => continue,
+                        };
+                        let bit = arg & (1 << 8) != 0;
+                        if bit && !last_bit {
+                            if ignored_edges == 0 {
+                                emergency = !emergency;
+                            } else {
+                                ignored_edges -= 1;
+                            }
+                        }
+                        last_bit = bit;
+                        flying = !emergency && arg & (1 << 9) != 0;
+                        if refs.last() != Some(&arg) {
+                            refs.push(arg);
+                        }
+                    }
+                }
+                let state = if emergency { EMERGENCY_BIT } else { 0 };
+                let control_state = if flying { 3 } else { 2 };
+                let _ = peer.send_navdata(&navdata(seq_num, state, control_state));
+                seq_num += 1;
+            }
+            refs
+        })
+    }
+
+    #[test]
+    fn emergency_is_not_sent_in_emergency_mode() {
+        let (mut drone, peer) = memory_drone();
+        peer.send_navdata(&navdata(1, EMERGENCY_BIT, 2)).unwrap();
+        wait_for_state(&drone, |s| s.emergency());
+
+        // The REF would take the drone out of emergency mode
+        drone.emergency();
+        assert!(peer.wait_for_command(|c| c.name == "REF").is_none());
+
+        peer.send_navdata(&navdata(2, 0, 2)).unwrap();
+        wait_for_state(&drone, |s| !s.emergency());
+        drone.emergency();
+        let sent = peer.wait_for_command(|c| c.name == "REF");
+        assert_eq!(sent.and_then(|c| c.int(0)), Some(REF_EMERGENCY));
+        drone.shutdown().unwrap();
+    }
+
+    #[test]
+    fn recover_from_emergency_retries_after_clearing_the_bit() {
+        let (mut drone, peer) = memory_drone();
+        let (stop, stopped) = mpsc::channel();
+        // The first rising edge is lost, the second one is seen
+        let fake = fake_drone(peer, true, 1, stopped);
+        wait_for_state(&drone, |s| s.emergency());
+
+        drone.recover_from_emergency(Duration::from_secs(3)).unwrap();
+        assert!(!drone.drone_state().unwrap().emergency());
+        drone.shutdown().unwrap();
+        stop.send(()).unwrap();
+        let refs = fake.join().unwrap();
+        assert_eq!(refs, vec![REF_EMERGENCY, REF_LAND, REF_EMERGENCY, REF_LAND]);
+    }
+
+    #[test]
+    fn recover_from_emergency_times_out() {
+        let (mut drone, peer) = memory_drone();
+        let (stop, stopped) = mpsc::channel();
+        let fake = fake_drone(peer, true, usize::MAX, stopped);
+        wait_for_state(&drone, |s| s.emergency());
+
+        let result = drone.recover_from_emergency(Duration::from_secs(1));
+        assert!(matches!(result, Err(DroneError::Timeout(_))));
+        drone.shutdown().unwrap();
+        stop.send(()).unwrap();
+        let refs = fake.join().unwrap();
+        // Every try is a new rising edge: the bit is cleared in between
+        assert!(refs.len() >= 4);
+        for pair in refs.chunks(2) {
+            assert_eq!(pair[0], REF_EMERGENCY);
+            if pair.len() == 2 {
+                assert_eq!(pair[1], REF_LAND);
+            }
+        }
+    }
+
+    #[test]
+    fn recover_from_emergency_without_emergency_sends_nothing() {
+        let (mut drone, peer) = memory_drone();
+        peer.send_navdata(&navdata(1, 0, 3)).unwrap();
+        wait_for_state(&drone, |s| !s.emergency());
+
+        drone.recover_from_emergency(Duration::from_secs(1)).unwrap();
+        assert!(peer.wait_for_command(|c| c.name == "REF").is_none());
+        drone.shutdown().unwrap();
+    }
+
+    #[test]
+    fn takeoff_and_land_are_confirmed_by_the_navdata() {
+        let (mut drone, peer) = memory_drone();
+        let (stop, stopped) = mpsc::channel();
+        let fake = fake_drone(peer, false, 0, stopped);
+        wait_for_state(&drone, |s| !s.emergency());
+
+        drone.takeoff_and_wait(Duration::from_secs(2)).unwrap();
+        drone.land_and_wait(Duration::from_secs(2)).unwrap();
+        drone.shutdown().unwrap();
+        stop.send(()).unwrap();
+        assert_eq!(fake.join().unwrap(), vec![REF_TAKEOFF, REF_LAND]);
+    }
+
+    #[test]
+    fn land_and_wait_ignores_packets_older_than_the_ref() {
+        let (mut drone, peer) = memory_drone();
+        // Landed already, but nothing is received after the REF
+        peer.send_navdata(&navdata(1, 0, 2)).unwrap();
+        wait_for_state(&drone, |s| !s.emergency());
+
+        let result = drone.land_and_wait(Duration::from_millis(300));
+        assert!(matches!(result, Err(DroneError::Timeout(_))));
+        assert!(peer.wait_for_command(|c| c.name == "REF").is_some());
+        drone.shutdown().unwrap();
+    }
+
+    #[test]
+    fn takeoff_and_wait_fails_in_emergency_mode() {
+        let (mut drone, peer) = memory_drone();
+        peer.send_navdata(&navdata(1, EMERGENCY_BIT, 2)).unwrap();
+        wait_for_state(&drone, |s| s.emergency());
+
+        let result = drone.takeoff_and_wait(Duration::from_secs(2));
+        assert!(matches!(result, Err(DroneError::Emergency)));
+        drone.shutdown().unwrap();
+    }
+
+    #[test]
+    fn land_and_wait_fails_while_the_link_is_lost() {
+        let (mut drone, peer) = memory_drone();
+        drone.navdata_reader().set_link_timeout(Duration::from_millis(100));
+        peer.send_navdata(&navdata(1, 0, 3)).unwrap();
+        wait_for_state(&drone, |s| !s.emergency());
+        while !drone.navdata_reader().no_navdata() {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let start = Instant::now();
+        let result = drone.land_and_wait(Duration::from_secs(2));
+        assert!(matches!(result, Err(DroneError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drone.shutdown().unwrap();
+    }
+
+    #[test]
+    fn navdata_options_are_configured_and_checked() {
+        let (mut drone, peer) = memory_drone();
+        let options = NavDataOptions::DEMO | NavDataOptions::TIME;
+        drone.set_navdata_options(options);
+        let config = peer.wait_for_command(|c| c.name == "CONFIG"
+            && c.string(0) == Some("general:navdata_options"));
+        assert_eq!(config.unwrap().string(1), Some("3"));
+        assert_eq!(drone.navdata_options(), options);
+
+        // The time block is missing
+        peer.send_navdata(&navdata(1, 0, 2)).unwrap();
+        let mut snapshot = NavDataSnapshot::default();
+        snapshot.header.header = NAVDATA_HEADER;
+        snapshot.header.seq_num = 2;
+        snapshot.demo = Some(DemoData::default());
+        snapshot.time = Some(Time { time: 1 });
+        peer.send_navdata(&NavDataPacket::from_snapshot(&snapshot).to_bytes()).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while drone.navdata_stats().packets_received < 2 {
+            assert!(Instant::now() < deadline, "navdata not received");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(drone.navdata_stats().incomplete_packets, 1);
+        drone.shutdown().unwrap();
+    }
+
+    #[test]
+    fn configs_the_drone_cannot_read_are_not_sent() {
+        let (mut drone, peer) = memory_drone();
+        drone.set_config_str("custom:name", "a,b");
+        drone.set_config_str("custom:name\r", "c");
+        drone.set_config("custom:name", String::from("\"d\""));
+        drone.set_config_str("custom:name", "e");
+        let config = peer.wait_for_command(|c| c.name == "CONFIG"
+            && c.string(0).is_some_and(|key| key.starts_with("custom:name")));
+        assert_eq!(config.unwrap().string(1), Some("e"));
+        drone.shutdown().unwrap();
+    }
+
+    #[test]
+    fn config_named_exit_does_not_stop_the_config_thread() {
+        let (mut drone, peer) = memory_drone();
+        peer.send_control(b"custom:exit = 1\nexit = 2\n").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while drone.get_offline_config("custom:exit").unwrap().is_none() {
+            assert!(Instant::now() < deadline, "config not received");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(drone.get_offline_config("exit").unwrap(), Some(String::from("2")));
+        assert_eq!(drone.get_offline_config("custom:exit").unwrap(), Some(String::from("1")));
+        drone.shutdown().unwrap();
+    }
+
+    #[test]
+    fn commands_are_sent_after_a_failed_send() {
+        let (mut drone, peer) = memory_drone();
+        peer.fail_sends(1);
+        drone.takeoff();
+        drone.land();
+
+        let land = peer.wait_for_command(|c| c.name == "REF" && c.int(0) == Some(REF_LAND));
+        assert!(land.is_some());
+        assert_eq!(drone.command_send_errors(), 1);
+        drone.shutdown().unwrap();
+    }
+
+    #[test]
+    fn emergency_after_reset_clears_the_bit_first() {
+        let (mut drone, peer) = memory_drone();
+        drone.reset();
+        drone.emergency();
+        thread::sleep(Duration::from_millis(300));
+
+        let mut refs: Vec<i32> = Vec::new();
+        for datagram in peer.at_datagrams() {
+            for command in parse_at_commands(datagram.as_bytes()).unwrap() {
+                match command.int(0) {
+                    Some(arg) if command.name == "REF" && refs.last() != Some(&arg) => refs.push(arg),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(refs, vec![REF_EMERGENCY, REF_LAND, REF_EMERGENCY]);
+        drone.shutdown().unwrap();
     }
 }
@@ -1,14 +1,43 @@
 use std::collections::HashMap;
-use std::net::UdpSocket;
-use std::thread;
+use std::{thread, time};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, PoisonError};
 use std::sync::mpsc::{self, TryRecvError, Sender, Receiver};
 use std::io::Cursor;
+use std::path::Path;
+use std::fmt;
+use std::error::Error;
 use byteorder::{LittleEndian, ReadBytesExt};
 
+use crate::navdata_options::{Header, NavDataSnapshot, NavDataOption, NavDataOptions, DemoData};
+use crate::navdata_history::{NavDataHistory, NavDataRecord};
+use crate::navdata_recording::{NavDataSource, NavDataRecorder, NavDataRecording, NavDataReplay};
+use crate::at_command::{AtCommand, CtrlMode};
+use crate::communication::{CommandSender, SenderMessage};
+use crate::error::DroneError;
+use crate::transport::{Transport, TransportNavData, is_timeout};
+
+/// Every navdata packet starts with this value
+pub const NAVDATA_HEADER: u32 = 0x5566_7788;
+/// Id of the checksum option block, which is the last one of every packet
+pub const NAVDATA_CHECKSUM_ID: u16 = 0xFFFF;
+/// How long the navdata thread waits for a packet before checking its
+/// control messages again
+const NAVDATA_READ_TIMEOUT: time::Duration = time::Duration::from_millis(100);
+/// Default time without valid packets after which the link is considered lost
+pub const NAVDATA_LINK_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+/// How long to wait for the command ACK during the navdata handshake before
+/// sending the configuration again
+const NAVDATA_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+/// A packet whose sequence number is smaller than the last one by more than
+/// this is treated as the start of a new session (e.g. the drone rebooted)
+const NAVDATA_SEQ_RESET_GAP: u32 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NavDataValue {
     Int(i32),
     Uint(u32),
     Float(f32),
+    Double(f64),
     Bool(bool)
 }
 
@@ -18,6 +47,7 @@ impl NavDataValue {
             NavDataValue::Int(a) => NavDataValue::Int(*a),
             NavDataValue::Uint(a) => NavDataValue::Uint(*a),
             NavDataValue::Float(a) => NavDataValue::Float(*a),
+            NavDataValue::Double(a) => NavDataValue::Double(*a),
             NavDataValue::Bool(a) => NavDataValue::Bool(*a),
         }
     }
@@ -29,209 +59,1234 @@ pub struct NavData {
     pub navdata_count: usize,
     pub navdata_timestamp: u32,
     pub navdata_decoding_time: f64,
-    pub no_navdata: bool,
-    command_sender: Option<Sender<String>>,
-    result_receiver: Option<Receiver<Option<NavDataValue>>>,
+    command_sender: Option<Sender<NavDataRequest>>,
+    reader: NavDataReader,
     join_handle: Option<thread::JoinHandle<()>>,
+    /// Listeners registered before the navdata thread was started
+    pending_listeners: Vec<NavDataListener>,
+    pending_event_listeners: Vec<Sender<NavDataEvent>>,
+    /// Recorder set before the navdata thread was started
+    pending_recorder: Option<NavDataRecorder>,
+}
+
+/// Everything the navdata thread decoded so far
+struct NavDataState {
+    options: HashMap<String, NavDataValue>,
+    latest: Option<NavDataSnapshot>,
+    stats: NavDataStats,
+    /// The option blocks requested from the drone, empty if unknown
+    expected_options: NavDataOptions,
+    /// Set when no valid packet arrived for longer than link_timeout
+    no_navdata: bool,
+    link_timeout: time::Duration,
+    /// Whether navdata_demo should be TRUE, sent during the handshake
+    demo_mode: bool,
 }
 
-fn decode_id0<'a, I: AsRef<[u8]>>(crs: &mut Cursor<I>,
-                                  options_map: &mut HashMap<String, NavDataValue>,
-                                  print_error: bool) {
-    let size;
-    match crs.read_u16::<LittleEndian>() {
-        Ok(a) => {
-            size = a;
+impl Default for NavDataState {
+    fn default() -> NavDataState {
+        NavDataState {
+            options: HashMap::new(),
+            latest: None,
+            stats: NavDataStats::default(),
+            expected_options: NavDataOptions::empty(),
+            no_navdata: false,
+            link_timeout: NAVDATA_LINK_TIMEOUT,
+            demo_mode: true,
         }
-        Err(e) => {
-            println!("Error: {}", e);
-            return;
+    }
+}
+
+/// Read access to the latest navdata. It can be cloned and sent to other
+/// threads, reading never waits for the navdata thread.
+#[derive(Clone, Default)]
+pub struct NavDataReader {
+    state: Arc<RwLock<NavDataState>>,
+    /// Behind its own lock, so reading the history doesn't hold back the
+    /// latest values
+    history: Arc<RwLock<NavDataHistory>>,
+}
+
+impl NavDataReader {
+    fn read(&self) -> RwLockReadGuard<'_, NavDataState> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, NavDataState> {
+        self.state.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_history(&self) -> RwLockWriteGuard<'_, NavDataHistory> {
+        self.history.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the latest value with the given name (see Drone::get_navdata)
+    pub fn get(&self, name: &str) -> Option<NavDataValue> {
+        self.read().options.get(name).copied()
+    }
+
+    /// Returns every value of the last navdata packet received
+    pub fn snapshot(&self) -> Option<NavDataSnapshot> {
+        self.read().latest.clone()
+    }
+
+    /// Returns the link quality of the current navdata session
+    pub fn stats(&self) -> NavDataStats {
+        self.read().stats
+    }
+
+    /// Returns a copy of the recent navdata packets. with_history doesn't
+    /// copy them.
+    pub fn history(&self) -> NavDataHistory {
+        self.with_history(NavDataHistory::clone)
+    }
+
+    /// Calls `f` with the recent navdata packets, new packets are added
+    /// once it returns.
+    /// ```
+    /// use parrot_ar_drone::*;
+    ///
+    /// let reader = NavDataReader::default();
+    /// let count = reader.with_history(|history| history.len());
+    /// assert_eq!(count, 0);
+    /// ```
+    pub fn with_history<R, F: FnOnce(&NavDataHistory) -> R>(&self, f: F) -> R {
+        f(&self.history.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Whether the navdata link is lost (no valid packet arrived for longer
+    /// than the link timeout)
+    pub fn no_navdata(&self) -> bool {
+        self.read().no_navdata
+    }
+
+    /// Sets how long the navdata thread waits for a valid packet before it
+    /// considers the link lost
+    pub fn set_link_timeout(&self, timeout: time::Duration) {
+        self.write().link_timeout = timeout;
+    }
+
+    /// Sets whether demo mode is requested from the drone when it asks for
+    /// its navdata configuration (bootstrap mode)
+    pub fn set_demo_mode(&self, demo_mode: bool) {
+        self.write().demo_mode = demo_mode;
+    }
+
+    /// The option blocks every packet should contain
+    pub fn expected_options(&self) -> NavDataOptions {
+        self.read().expected_options
+    }
+
+    /// Sets the option blocks every packet should contain, packets without
+    /// them are counted in the stats as incomplete.
+    pub fn set_expected_options(&self, options: NavDataOptions) {
+        self.write().expected_options = options;
+    }
+
+    /// Changes how many packets are kept in the history
+    pub fn set_history_size(&self, size: usize) {
+        self.write_history().set_capacity(size);
+    }
+}
+
+/// Something that is notified about every new navdata packet
+enum NavDataListener {
+    Channel(Sender<NavDataSnapshot>),
+    Callback(Box<dyn FnMut(&NavDataSnapshot) + Send>),
+}
+
+impl NavDataListener {
+    /// Returns false if the listener is gone and should be removed
+    fn notify(&mut self, snapshot: &NavDataSnapshot) -> bool {
+        match self {
+            NavDataListener::Channel(sender) => sender.send(snapshot.clone()).is_ok(),
+            NavDataListener::Callback(callback) => {
+                callback(snapshot);
+                true
+            }
+        }
+    }
+}
+
+/// Link quality of the current navdata session. A session starts with the
+/// first packet and again after a sequence number reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NavDataStats {
+    /// Valid packets accepted
+    pub packets_received: u64,
+    /// Packets which never arrived (gaps in the sequence numbers)
+    pub packets_dropped: u64,
+    /// Packets which arrived after a newer one, they are discarded
+    pub out_of_order: u64,
+    /// Packets with the same sequence number as the last one, they are
+    /// discarded
+    pub duplicates: u64,
+    /// Packets dropped because their checksum did not match
+    pub checksum_failures: u64,
+    /// Packets dropped because they could not be decoded
+    pub parse_errors: u64,
+    /// Packets which lacked some of the requested option blocks (they are
+    /// kept)
+    pub incomplete_packets: u64,
+    /// Smoothed time between two accepted packets
+    pub mean_interval: time::Duration,
+    /// Smoothed deviation of the time between two packets from the mean
+    /// (like the RTP interarrival jitter)
+    pub jitter: time::Duration,
+    /// When the first packet of the session arrived
+    pub session_start: Option<time::Instant>,
+    /// When the last accepted packet arrived
+    pub last_packet: Option<time::Instant>,
+}
+
+impl NavDataStats {
+    /// Accepted packets per second since the start of the session
+    pub fn rate(&self) -> f32 {
+        match (self.session_start, self.last_packet) {
+            (Some(start), Some(last)) if last > start => {
+                (self.packets_received - 1) as f32 / (last - start).as_secs_f32()
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// The share of the packets sent by the drone which did not arrive
+    pub fn loss_ratio(&self) -> f32 {
+        let sent = self.packets_received + self.packets_dropped;
+        if sent == 0 {
+            0.0
+        } else {
+            self.packets_dropped as f32 / sent as f32
+        }
+    }
+
+    /// Counts an accepted packet with sequence number gap `gap` (1 if none
+    /// is missing) and updates the timing estimates.
+    fn packet_received(&mut self, gap: u32, now: time::Instant) {
+        if let Some(last) = self.last_packet {
+            self.packets_dropped += u64::from(gap.saturating_sub(1));
+            let interval = now.duration_since(last).as_secs_f64();
+            if self.packets_received == 1 {
+                self.mean_interval = time::Duration::from_secs_f64(interval);
+            } else {
+                let mean = self.mean_interval.as_secs_f64();
+                let jitter = self.jitter.as_secs_f64();
+                self.jitter = time::Duration::from_secs_f64(
+                    jitter + ((interval - mean).abs() - jitter) / 16.0);
+                self.mean_interval = time::Duration::from_secs_f64(
+                    mean + (interval - mean) / 16.0);
+            }
+        } else {
+            self.session_start = Some(now);
+        }
+        self.last_packet = Some(now);
+        self.packets_received += 1;
+    }
+}
+
+/// The reasons a navdata packet can be rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NavDataError {
+    /// The packet does not start with NAVDATA_HEADER
+    WrongHeader(u32),
+    /// The packet ended in the middle of the header or of an option block
+    Truncated,
+    /// The size of an option block is smaller than its own header, or the
+    /// demo or checksum block does not have its fixed size
+    WrongOptionSize { id: u16, size: u16 },
+    /// The checksum sent by the drone does not match the packet
+    WrongChecksum { expected: u32, computed: u32 },
+}
+
+impl fmt::Display for NavDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NavDataError::WrongHeader(header) => {
+                write!(f, "navdata packet has wrong header: {:#x}", header)
+            }
+            NavDataError::Truncated => {
+                write!(f, "navdata packet is truncated")
+            }
+            NavDataError::WrongOptionSize { id, size } => {
+                write!(f, "navdata option {} has wrong size: {}", id, size)
+            }
+            NavDataError::WrongChecksum { expected, computed } => {
+                write!(f, "navdata checksum mismatch: expected {}, computed {}", expected, computed)
+            }
+        }
+    }
+}
+
+impl Error for NavDataError {}
+
+/// A navdata packet exactly as it was sent by the drone
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavDataPacket {
+    pub header: Header,
+    /// The option blocks in the order they were sent (without the checksum)
+    pub options: Vec<NavDataOption>,
+    /// The checksum sent by the drone (already verified)
+    pub checksum: u32,
+}
+
+impl NavDataPacket {
+    /// Collects the option blocks of the packet into a snapshot
+    pub fn snapshot(&self) -> NavDataSnapshot {
+        let mut snapshot = NavDataSnapshot {
+            header: self.header,
+            ..NavDataSnapshot::default()
+        };
+        for option in &self.options {
+            snapshot.apply(option);
+        }
+        snapshot
+    }
+
+    /// Encodes the packet the way the drone sends it, the checksum block is
+    /// computed and appended (the checksum field is ignored).
+    /// ```
+    /// use parrot_ar_drone::*;
+    ///
+    /// let mut snapshot = NavDataSnapshot::default();
+    /// snapshot.header.header = NAVDATA_HEADER;
+    /// snapshot.header.seq_num = 3;
+    /// snapshot.demo = Some(DemoData { battery: 80, ..DemoData::default() });
+    /// snapshot.time = Some(Time { time: 1 << 11 });
+    ///
+    /// let packet = NavDataPacket::from_snapshot(&snapshot);
+    /// let decoded = parse_navdata(&packet.to_bytes()).unwrap();
+    /// assert_eq!(decoded.snapshot(), snapshot);
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.header.write_to(&mut out);
+        for option in &self.options {
+            option.write_to(&mut out);
         }
+        let checksum = compute_checksum(&out);
+        out.extend_from_slice(&NAVDATA_CHECKSUM_ID.to_le_bytes());
+        out.extend_from_slice(&8_u16.to_le_bytes());
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
     }
-    if size != 148 && print_error {
-        println!("Navdata-Demo-Packet has wrong size: {}", size);
-        return;
+
+    /// A packet with every option block of the snapshot
+    pub fn from_snapshot(snapshot: &NavDataSnapshot) -> NavDataPacket {
+        NavDataPacket {
+            header: snapshot.header,
+            options: snapshot.to_options(),
+            checksum: 0,
+        }
     }
-    let flags = crs.read_u32::<LittleEndian>().unwrap();
-    options_map.insert(String::from("demo_default"), NavDataValue::Bool(flags >> 15 & 1 == 1));
-    options_map.insert(String::from("demo_init"), NavDataValue::Bool(flags >> 16 & 1 == 1));
-    options_map.insert(String::from("demo_landed"), NavDataValue::Bool(flags >> 17 & 1 == 1));
-    options_map.insert(String::from("demo_flying"), NavDataValue::Bool(flags >> 18 & 1 == 1));
-    options_map.insert(String::from("demo_hovering"), NavDataValue::Bool(flags >> 19 & 1 == 1));
-    options_map.insert(String::from("demo_test"), NavDataValue::Bool(flags >> 20 & 1 == 1));
-    options_map.insert(String::from("demo_trans_takeoff"), NavDataValue::Bool(flags >> 21 & 1 == 1));
-    options_map.insert(String::from("demo_trans_gofix"), NavDataValue::Bool(flags >> 22 & 1 == 1));
-    options_map.insert(String::from("demo_trans_landing"), NavDataValue::Bool(flags >> 23 & 1 == 1));
-    options_map.insert(String::from("demo_trans_looping"), NavDataValue::Bool(flags >> 24 & 1 == 1));
-    options_map.insert(String::from("demo_trans_no_vision"), NavDataValue::Bool(flags >> 25 & 1 == 1));
-    options_map.insert(String::from("demo_num_state"),NavDataValue::Bool(flags >> 26 & 1 == 1));
+}
+
+/// Something that happened to the navdata link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NavDataEvent {
+    /// No valid packet arrived for longer than the link timeout
+    LinkLost,
+    /// Packets arrive again after the link was lost
+    LinkRestored,
+    /// A packet was dropped because it could not be decoded
+    PacketDropped(NavDataError),
+    /// The sequence number started again (e.g. the drone rebooted)
+    SessionReset,
+    /// The drone asked for its navdata configuration (bootstrap mode), it
+    /// was sent
+    Bootstrap,
+    /// The drone acknowledged the navdata configuration, navdata is sent
+    HandshakeDone,
+}
 
-    let battery = crs.read_u32::<LittleEndian>().unwrap();
-    options_map.insert(String::from("demo_battery"),NavDataValue::Uint(battery));
+/// Messages sent to the navdata thread
+enum NavDataRequest {
+    Listen(NavDataListener),
+    ListenEvents(Sender<NavDataEvent>),
+    Record(Option<NavDataRecorder>),
+    Exit,
+}
 
-    let theta = crs.read_f32::<LittleEndian>().unwrap();
-    let phi = crs.read_f32::<LittleEndian>().unwrap();
-    let psi = crs.read_f32::<LittleEndian>().unwrap();
-    options_map.insert(String::from("demo_theta"),NavDataValue::Float(theta));
-    options_map.insert(String::from("demo_phi"),NavDataValue::Float(phi));
-    options_map.insert(String::from("demo_psi"),NavDataValue::Float(psi));
+/// Everything the navdata thread works with
+struct NavDataWorker {
+    stream: Box<dyn NavDataSource>,
+    print_error: bool,
+    reader: NavDataReader,
+    listeners: Vec<NavDataListener>,
+    event_listeners: Vec<Sender<NavDataEvent>>,
+    recorder: Option<NavDataRecorder>,
+    seq_num: u32,
+    /// When the last valid packet arrived (or the thread started)
+    last_packet: time::Instant,
+    /// When the wake-up datagram was last sent
+    last_wake_up: time::Instant,
+    link_lost: bool,
+    /// Commands sent during the navdata handshake, None when replaying
+    commands: Option<CommandSender>,
+    /// When the navdata configuration was sent, if the ACK is not there yet
+    waiting_for_ack: Option<time::Instant>,
+}
 
-    let altitude = crs.read_i32::<LittleEndian>().unwrap() / 10;
-    options_map.insert(String::from("demo_altitude"),NavDataValue::Int(altitude));
+impl NavDataWorker {
+    fn wake_up(&mut self) {
+        self.last_wake_up = time::Instant::now();
+        if let Err(e) = self.stream.wake_up() {
+            if self.print_error {
+                println!("Couldn't wake up navdata: {}", e);
+            }
+        }
+    }
 
-    let vx = crs.read_f32::<LittleEndian>().unwrap();
-    let vy = crs.read_f32::<LittleEndian>().unwrap();
-    let vz = crs.read_f32::<LittleEndian>().unwrap();
-    options_map.insert(String::from("demo_vx"),NavDataValue::Float(vx));
-    options_map.insert(String::from("demo_vy"),NavDataValue::Float(vy));
-    options_map.insert(String::from("demo_vz"),NavDataValue::Float(vz));
+    fn emit(&mut self, event: NavDataEvent) {
+        self.event_listeners.retain(|l| l.send(event).is_ok());
+    }
 
-    let num_frames = crs.read_u32::<LittleEndian>().unwrap();
-    options_map.insert(String::from("demo_num_frames"),NavDataValue::Uint(num_frames));
+    fn handle_datagram(&mut self, data: &[u8]) {
+        if let Some(rec) = &mut self.recorder {
+            if let Err(e) = rec.write(data) {
+                if self.print_error {
+                    println!("Navdata recording stopped: {}", e);
+                }
+                self.recorder = None;
+            }
+        }
+        match parse_navdata(data) {
+            Ok(packet) => {
+                let seq = packet.header.seq_num;
+                if seq > self.seq_num {
+                    let gap = seq - self.seq_num;
+                    self.seq_num = seq;
+                    self.handle_packet(&packet, gap);
+                } else if self.seq_num - seq > NAVDATA_SEQ_RESET_GAP || self.link_lost {
+                    // A rebooted drone starts again in bootstrap mode, but
+                    // the bootstrap bit alone is no reset: it stays set
+                    // until the configuration is sent, also in late packets
+                    self.seq_num = seq;
+                    self.reader.write().stats = NavDataStats::default();
+                    self.emit(NavDataEvent::SessionReset);
+                    self.handle_packet(&packet, 1);
+                } else if seq == self.seq_num {
+                    self.reader.write().stats.duplicates += 1;
+                } else {
+                    self.reader.write().stats.out_of_order += 1;
+                }
+            }
+            Err(e) => {
+                {
+                    let mut state = self.reader.write();
+                    if let NavDataError::WrongChecksum { .. } = e {
+                        state.stats.checksum_failures += 1;
+                    } else {
+                        state.stats.parse_errors += 1;
+                    }
+                }
+                if self.print_error {
+                    println!("Dropped navdata packet: {}", e);
+                }
+                self.emit(NavDataEvent::PacketDropped(e));
+            }
+        }
+    }
 
-    for i in 0..9 {
-        let val = crs.read_f32::<LittleEndian>().unwrap();
-        options_map.insert(format!("demo_det_cam_rot_{}", i),NavDataValue::Float(val));
+    /// Handles an accepted packet, `gap` is the difference to the sequence
+    /// number of the previous one.
+    fn handle_packet(&mut self, packet: &NavDataPacket, gap: u32) {
+        let snapshot = packet.snapshot();
+        let now = time::Instant::now();
+        {
+            let mut state = self.reader.write();
+            state.stats.packet_received(gap, now);
+            if !snapshot.options().contains(state.expected_options) {
+                state.stats.incomplete_packets += 1;
+            }
+            snapshot.insert_into(&mut state.options);
+            state.latest = Some(snapshot.clone());
+            state.no_navdata = false;
+        }
+        self.reader.write_history().push(NavDataRecord {
+            received: now,
+            snapshot: snapshot.clone(),
+        });
+        self.last_packet = now;
+        if self.link_lost {
+            self.link_lost = false;
+            self.emit(NavDataEvent::LinkRestored);
+        }
+        self.listeners.retain_mut(|l| l.notify(&snapshot));
+        self.handshake(&snapshot);
     }
-    for i in 0..3 {
-        let val = crs.read_f32::<LittleEndian>().unwrap();
-        options_map.insert(format!("demo_det_cam_trans_{}", i),NavDataValue::Float(val));
+
+    fn command(&self, command: AtCommand) {
+        if let Some(commands) = &self.commands {
+            let _ = commands.send(SenderMessage::Command(command));
+        }
     }
-    let det_tag_index = crs.read_u32::<LittleEndian>().unwrap();
-    options_map.insert(String::from("demo_detection_tag_index"),NavDataValue::Uint(det_tag_index));
 
-    let det_tag_type = crs.read_u32::<LittleEndian>().unwrap();
-    options_map.insert(String::from("demo_detection_tag_type"),NavDataValue::Uint(det_tag_type));
+    /// The navdata initialization of the SDK: when the drone is in bootstrap
+    /// mode the navdata configuration is sent, then the command ACK bit is
+    /// acknowledged with CTRL 5.
+    fn handshake(&mut self, snapshot: &NavDataSnapshot) {
+        if self.commands.is_none() {
+            return;
+        }
+        let drone_state = snapshot.header.drone_state;
+        if let Some(since) = self.waiting_for_ack {
+            if drone_state.command_ack() {
+                self.command(AtCommand::Ctrl(CtrlMode::Ack));
+                self.waiting_for_ack = None;
+                self.emit(NavDataEvent::HandshakeDone);
+                return;
+            }
+            if since.elapsed() < NAVDATA_HANDSHAKE_TIMEOUT {
+                return;
+            }
+        } else if !drone_state.navdata_bootstrap() {
+            return;
+        }
 
-    for i in 0..9 {
-        let val = crs.read_f32::<LittleEndian>().unwrap();
-        options_map.insert(format!("demo_cam_rot_{}", i),NavDataValue::Float(val));
+        let (demo_mode, options) = {
+            let state = self.reader.read();
+            (state.demo_mode, state.expected_options)
+        };
+        let demo = if demo_mode { "TRUE" } else { "FALSE" };
+        self.command(AtCommand::Config {
+            key: String::from("general:navdata_demo"),
+            value: String::from(demo),
+        });
+        if !options.is_empty() {
+            self.command(AtCommand::Config {
+                key: String::from("general:navdata_options"),
+                value: options.bits().to_string(),
+            });
+        }
+        if self.waiting_for_ack.is_none() {
+            self.emit(NavDataEvent::Bootstrap);
+        }
+        self.waiting_for_ack = Some(time::Instant::now());
     }
-    for i in 0..3 {
-        let val = crs.read_f32::<LittleEndian>().unwrap();
-        options_map.insert(format!("demo_cam_trans_{}", i),NavDataValue::Float(val));
+
+    /// Notices when packets stop arriving and keeps asking the drone for
+    /// navdata until they arrive again.
+    fn check_link(&mut self) {
+        let timeout = self.reader.read().link_timeout;
+        if self.last_packet.elapsed() <= timeout {
+            return;
+        }
+        if !self.link_lost {
+            self.link_lost = true;
+            self.reader.write().no_navdata = true;
+            if self.print_error {
+                println!("Navdata link lost");
+            }
+            self.emit(NavDataEvent::LinkLost);
+        }
+        if self.last_wake_up.elapsed() > timeout {
+            self.wake_up();
+        }
     }
 }
 
-fn get_navdata_thread(op_stream: Option<UdpSocket>,
+/// What the navdata thread starts with
+struct NavDataThreadConfig {
+    source: Box<dyn NavDataSource>,
+    /// Listeners registered before the thread was started
+    listeners: Vec<NavDataListener>,
+    event_listeners: Vec<Sender<NavDataEvent>>,
+    recorder: Option<NavDataRecorder>,
+    /// Commands sent during the navdata handshake, None when replaying
+    commands: Option<CommandSender>,
+}
+
+fn get_navdata_thread(config: NavDataThreadConfig,
                       print_error: bool,
-                      command_receiver: Receiver<String>,
-                      result_sender: Sender<Option<NavDataValue>>) {
-    let stream = op_stream.unwrap();
-    let mut options: HashMap<String, NavDataValue> = HashMap::new();
-
-    let tmp = [1 as u8, 0 as u8, 0 as u8, 0 as u8];
-    let mut seq_num = 0;
-    stream.send(&tmp).unwrap();
+                      command_receiver: Receiver<NavDataRequest>,
+                      reader: NavDataReader) {
+    let now = time::Instant::now();
+    let mut worker = NavDataWorker {
+        stream: config.source,
+        print_error,
+        reader,
+        listeners: config.listeners,
+        event_listeners: config.event_listeners,
+        recorder: config.recorder,
+        seq_num: 0,
+        last_packet: now,
+        last_wake_up: now,
+        link_lost: false,
+        commands: config.commands,
+        waiting_for_ack: None,
+    };
+    let mut buff = vec![0; 65535];
+    worker.wake_up();
     loop {
         match command_receiver.try_recv() {
-            Ok(option_name) => {
-                if option_name == "exit" {
-                    break;
+            Ok(NavDataRequest::Listen(listener)) => {
+                worker.listeners.push(listener);
+            }
+            Ok(NavDataRequest::ListenEvents(listener)) => {
+                worker.event_listeners.push(listener);
+            }
+            Ok(NavDataRequest::Record(new_recorder)) => {
+                if let Some(mut old) = worker.recorder.take() {
+                    let _ = old.flush();
                 }
-                result_sender.send(options.get(&option_name)
-                                   .map(|a| a.copy())).unwrap();
+                worker.recorder = new_recorder;
             }
-            Err(TryRecvError::Disconnected) => {
+            Ok(NavDataRequest::Exit) | Err(TryRecvError::Disconnected) => {
                 break;
             }
             Err(TryRecvError::Empty) => {}
         }
-        let mut buff = [0; 65535];
-        match stream.recv(&mut buff) {
-            Ok(_) => {
-                let mut crs = Cursor::new(buff.iter());
-                let header = crs.read_u32::<LittleEndian>().unwrap();
-                let drone_state = crs.read_u32::<LittleEndian>().unwrap();
-                let packet_seq = crs.read_u32::<LittleEndian>().unwrap();
-                let vision_flag = crs.read_u32::<LittleEndian>().unwrap();
-
-                if packet_seq > seq_num {
-                    seq_num = packet_seq;
-                    options.insert(String::from("header_header"), NavDataValue::Uint(header));
-                    options.insert(String::from("header_seq_num"), NavDataValue::Uint(seq_num));
-                    options.insert(String::from("header_drone_state"), NavDataValue::Uint(drone_state));
-                    options.insert(String::from("header_flag"), NavDataValue::Uint(vision_flag));
-                    loop {
-                        let id = crs.read_u16::<LittleEndian>().unwrap();
-                        if id == 0 {
-                            decode_id0(&mut crs, &mut options, print_error);
-                        } else if id != 65535 { // Checksum packet
-                            let mut size = crs.read_u32::<LittleEndian>().unwrap();
-                            while size > 32 {
-                                let _tmp = crs.read_u32::<LittleEndian>().unwrap();
-                                size -= 32;
-                            }
-                            while size > 8 {
-                                let _tmp = crs.read_u8().unwrap();
-                                size -= 8;
-                            }
-                            break;
-                        }
+        match worker.stream.recv(&mut buff) {
+            Ok(len) => worker.handle_datagram(&buff[..len]),
+            Err(ref e) if is_timeout(e) => {}
+            // E.g. ICMP port unreachable, don't spin until the drone is back
+            Err(_) => thread::sleep(NAVDATA_READ_TIMEOUT),
+        }
+        worker.check_link();
+    }
+}
 
-                    }
-                }
-            }
-            _ => {
+/// The checksum of a navdata packet is the sum of every byte before the
+/// checksum option block.
+fn compute_checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0_u32, |acc, b| acc.wrapping_add(u32::from(*b)))
+}
+
+/// Decodes a whole navdata packet (the payload of a single UDP datagram).
+///
+/// Every option block starts with its id and its size (which includes these
+/// 4 bytes), the last one is the checksum. Option blocks with an unknown id
+/// are kept as `NavDataOption::Unknown`, so are known blocks too short for
+/// their struct. This function never panics, any malformed input is reported
+/// as an error.
+/// ```
+/// use parrot_ar_drone::*;
+///
+/// let mut data = Vec::new();
+/// data.extend_from_slice(&NAVDATA_HEADER.to_le_bytes());
+/// data.extend_from_slice(&0_u32.to_le_bytes()); // drone state
+/// data.extend_from_slice(&42_u32.to_le_bytes()); // sequence number
+/// data.extend_from_slice(&0_u32.to_le_bytes()); // vision flag
+/// data.extend_from_slice(&[100, 0, 6, 0, 1, 2]); // unknown option 100
+/// let checksum: u32 = data.iter().map(|b| u32::from(*b)).sum();
+/// data.extend_from_slice(&[0xFF, 0xFF, 8, 0]);
+/// data.extend_from_slice(&checksum.to_le_bytes());
+///
+/// let packet = parse_navdata(&data).unwrap();
+/// assert_eq!(packet.header.seq_num, 42);
+/// assert_eq!(packet.options, vec![NavDataOption::Unknown { id: 100, data: vec![1, 2] }]);
+///
+/// assert_eq!(parse_navdata(&data[..20]), Err(NavDataError::Truncated));
+/// ```
+pub fn parse_navdata(data: &[u8]) -> Result<NavDataPacket, NavDataError> {
+    let mut crs = Cursor::new(data);
+    let header = Header::read_from(&mut crs).map_err(|_| NavDataError::Truncated)?;
+    if header.header != NAVDATA_HEADER {
+        return Err(NavDataError::WrongHeader(header.header));
+    }
+    let mut options = Vec::new();
+
+    loop {
+        let start = crs.position() as usize;
+        let id = crs.read_u16::<LittleEndian>().map_err(|_| NavDataError::Truncated)?;
+        let size = crs.read_u16::<LittleEndian>().map_err(|_| NavDataError::Truncated)?;
+        if size < 4 {
+            return Err(NavDataError::WrongOptionSize { id, size });
+        }
+        let end = start + size as usize;
+        if end > data.len() {
+            return Err(NavDataError::Truncated);
+        }
+        let mut option_crs = Cursor::new(&data[start + 4..end]);
 
+        if id == NAVDATA_CHECKSUM_ID {
+            let expected = option_crs.read_u32::<LittleEndian>()
+                .map_err(|_| NavDataError::WrongOptionSize { id, size })?;
+            let computed = compute_checksum(&data[..start]);
+            if expected != computed {
+                return Err(NavDataError::WrongChecksum { expected, computed });
             }
+            return Ok(NavDataPacket {
+                header,
+                options,
+                checksum: expected,
+            });
+        }
+        if id == DemoData::ID && size != 148 {
+            return Err(NavDataError::WrongOptionSize { id, size });
         }
+        // A known block shorter than its struct (e.g. from another firmware
+        // version) is kept as is instead of rejecting the whole packet
+        let option = NavDataOption::read_from(id, &mut option_crs)
+            .unwrap_or_else(|_| NavDataOption::Unknown { id, data: data[start + 4..end].to_vec() });
+        options.push(option);
+        crs.set_position(end as u64);
     }
 }
 
 impl NavData {
     /// Returns a NavData object with default settings
     pub fn new() -> NavData {
-        return NavData {
+        NavData {
             navdata: String::new(),
             state: vec![32; 0],
             navdata_count: 0,
             navdata_timestamp: 0,
             navdata_decoding_time: 0.0,
-            no_navdata: false,
             command_sender: None,
-            result_receiver: None,
+            reader: NavDataReader::default(),
             join_handle: None,
-        };
+            pending_listeners: Vec::new(),
+            pending_event_listeners: Vec::new(),
+            pending_recorder: None,
+        }
+    }
+
+    pub fn get_navdata(&self, name: String) -> Option<NavDataValue> {
+        self.reader.get(&name)
+    }
+
+    pub fn get_navdata_str(&self, name: &str) -> Option<NavDataValue> {
+        self.reader.get(name)
+    }
+
+    /// Returns every value of the last navdata packet received
+    pub fn get_snapshot(&self) -> Option<NavDataSnapshot> {
+        self.reader.snapshot()
+    }
+
+    /// Returns the link quality of the current navdata session
+    pub fn get_stats(&self) -> NavDataStats {
+        self.reader.stats()
+    }
+
+    /// Returns a handle which can read the latest navdata from any thread
+    pub fn reader(&self) -> NavDataReader {
+        self.reader.clone()
+    }
+
+    /// Sends a control message to the navdata thread
+    fn request(&self, request: NavDataRequest) -> Result<(), DroneError> {
+        match &self.command_sender {
+            Some(sender) => sender.send(request).map_err(|_| DroneError::ThreadDied("navdata")),
+            None => Err(DroneError::NotConnected),
+        }
+    }
+
+    fn add_listener(&mut self, listener: NavDataListener) -> Result<(), DroneError> {
+        if self.command_sender.is_none() {
+            self.pending_listeners.push(listener);
+            return Ok(());
+        }
+        self.request(NavDataRequest::Listen(listener))
+    }
+
+    /// Returns a receiver which gets every new navdata packet. If the navdata
+    /// thread stopped the receiver is disconnected.
+    pub fn subscribe(&mut self) -> Receiver<NavDataSnapshot> {
+        let (sender, receiver) = mpsc::channel();
+        let _ = self.add_listener(NavDataListener::Channel(sender));
+        receiver
+    }
+
+    /// Returns a receiver which gets the events of the navdata link. If the
+    /// navdata thread stopped the receiver is disconnected.
+    pub fn subscribe_events(&mut self) -> Receiver<NavDataEvent> {
+        let (sender, receiver) = mpsc::channel();
+        if self.command_sender.is_none() {
+            self.pending_event_listeners.push(sender);
+        } else {
+            let _ = self.request(NavDataRequest::ListenEvents(sender));
+        }
+        receiver
     }
 
+    /// Whether the navdata link is lost
+    pub fn no_navdata(&self) -> bool {
+        self.reader.no_navdata()
+    }
+
+    /// The callback is called from the navdata thread with every new packet
+    pub fn on_navdata<F>(&mut self, callback: F) -> Result<(), DroneError>
+        where F: FnMut(&NavDataSnapshot) + Send + 'static {
+        self.add_listener(NavDataListener::Callback(Box::new(callback)))
+    }
 
-    pub fn get_navdata(&mut self, name: String) -> Option<NavDataValue> {
-        let cmd_sender = self.command_sender.take().unwrap();
-        cmd_sender.send(name).unwrap();
-        self.command_sender.replace(cmd_sender);
+    /// Starts writing every received navdata datagram to the file
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P) -> Result<(), DroneError> {
+        let recorder = NavDataRecorder::create(path)?;
+        if self.command_sender.is_none() {
+            self.pending_recorder = Some(recorder);
+            return Ok(());
+        }
+        self.request(NavDataRequest::Record(Some(recorder)))
+    }
 
-        let res_rec = self.result_receiver.take().unwrap();
-        let recv_result = res_rec.recv();
-        self.result_receiver.replace(res_rec);
-        match recv_result {
-            Ok(res) => { res }
-            _ => { None }
+    pub fn stop_recording(&mut self) -> Result<(), DroneError> {
+        if self.command_sender.is_none() {
+            self.pending_recorder = None;
+            return Ok(());
         }
+        self.request(NavDataRequest::Record(None))
+    }
+
+    /// Starts the navdata thread, reading from the transport. The commands
+    /// of the navdata handshake are sent through `commands`.
+    pub(crate) fn start_navdata_listening_thread(&mut self,
+                                                 transport: Arc<dyn Transport>,
+                                                 print_error: bool,
+                                                 commands: Option<CommandSender>) {
+        self.start_thread(Box::new(TransportNavData(transport)), print_error, commands);
     }
 
-    pub fn get_navdata_str(&mut self, name: &str) -> Option<NavDataValue> {
-        self.get_navdata(String::from(name))
+    /// Starts the navdata thread with a recording instead of the drone, the
+    /// thread reading from the drone is stopped. A speed of 1.0 replays it
+    /// in real time.
+    pub fn start_navdata_replay_thread(&mut self,
+                                       recording: NavDataRecording,
+                                       speed: f32,
+                                       print_error: bool) {
+        let replay = NavDataReplay::new(recording, speed, NAVDATA_READ_TIMEOUT);
+        self.start_thread(Box::new(replay), print_error, None);
     }
 
-    pub fn start_navdata_listening_thread(&mut self,
-                                          tcp_stream: UdpSocket,
-                                          print_error: bool) {
+    /// Starts the navdata thread, a running one is stopped first
+    fn start_thread(&mut self,
+                    source: Box<dyn NavDataSource>,
+                    print_error: bool,
+                    commands: Option<CommandSender>) {
+        if let Err(e) = self.stop_navdata_listening_thread() {
+            if print_error {
+                println!("Previous navdata thread stopped with an error: {}", e);
+            }
+        }
         let (c_s, c_r) = mpsc::channel();
-        let (r_s, r_r) = mpsc::channel();
         self.command_sender = Some(c_s);
-        self.result_receiver = Some(r_r);
+        let reader = self.reader.clone();
+        let config = NavDataThreadConfig {
+            source,
+            listeners: self.pending_listeners.drain(..).collect(),
+            event_listeners: self.pending_event_listeners.drain(..).collect(),
+            recorder: self.pending_recorder.take(),
+            commands,
+        };
         self.join_handle = Some(thread::spawn(move || {
-            get_navdata_thread(Some(tcp_stream),
-            print_error,
-            c_r,
-            r_s);
+            get_navdata_thread(config, print_error, c_r, reader);
         }));
     }
 
-    pub fn stop_navdata_listening_thread(&mut self) {
-        self.command_sender.take().unwrap().send(String::from("exit")).unwrap();
-        self.result_receiver.take().unwrap();
-        self.join_handle.take().unwrap().join().unwrap();
+    /// Stops the navdata thread, returns an error if it panicked
+    pub fn stop_navdata_listening_thread(&mut self) -> Result<(), DroneError> {
+        if let Some(sender) = self.command_sender.take() {
+            let _ = sender.send(NavDataRequest::Exit);
+        }
+        match self.join_handle.take() {
+            Some(handle) => handle.join().map_err(|_| DroneError::ThreadDied("navdata")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for NavData {
+    fn default() -> NavData {
+        NavData::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::navdata_options::{ControlState, DroneState, Time};
+    use crate::transport::{MemoryPeer, MemoryTransport};
+
+    const COMMAND_ACK: u32 = 1 << 6;
+    const NAVDATA_DEMO: u32 = 1 << 10;
+    const NAVDATA_BOOTSTRAP: u32 = 1 << 11;
+    const WAIT: time::Duration = time::Duration::from_secs(2);
+
+    fn packet(seq_num: u32, drone_state: u32) -> Vec<u8> {
+        let mut snapshot = NavDataSnapshot::default();
+        snapshot.header.header = NAVDATA_HEADER;
+        snapshot.header.drone_state = DroneState(drone_state);
+        snapshot.header.seq_num = seq_num;
+        NavDataPacket::from_snapshot(&snapshot).to_bytes()
+    }
+
+    /// A navdata thread reading from a MemoryPeer, the handshake commands
+    /// go to the returned receiver
+    fn memory_navdata() -> (NavData, MemoryPeer, Receiver<SenderMessage>) {
+        let (transport, peer) = MemoryTransport::new();
+        let (commands, command_receiver) = mpsc::channel();
+        let mut navdata = NavData::new();
+        navdata.start_navdata_listening_thread(Arc::new(transport), false, Some(commands));
+        (navdata, peer, command_receiver)
+    }
+
+    fn next_command(commands: &Receiver<SenderMessage>) -> Option<AtCommand> {
+        match commands.recv_timeout(WAIT) {
+            Ok(SenderMessage::Command(command)) => Some(command),
+            _ => None,
+        }
+    }
+
+    /// Waits for `event`, the other events are skipped
+    fn wait_for_event(events: &Receiver<NavDataEvent>, event: NavDataEvent) -> bool {
+        loop {
+            match events.recv_timeout(WAIT) {
+                Ok(e) if e == event => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Sends the packet and waits until it is accepted
+    fn send_accepted(peer: &MemoryPeer, snapshots: &Receiver<NavDataSnapshot>, data: &[u8]) {
+        peer.send_navdata(data).unwrap();
+        snapshots.recv_timeout(WAIT).unwrap();
+    }
+
+    #[test]
+    fn handshake_sends_the_config_then_acknowledges() {
+        let (mut navdata, peer, commands) = memory_navdata();
+        let events = navdata.subscribe_events();
+
+        peer.send_navdata(&packet(1, NAVDATA_BOOTSTRAP)).unwrap();
+        assert_eq!(next_command(&commands), Some(AtCommand::Config {
+            key: String::from("general:navdata_demo"),
+            value: String::from("TRUE"),
+        }));
+        assert!(wait_for_event(&events, NavDataEvent::Bootstrap));
+
+        peer.send_navdata(&packet(2, NAVDATA_DEMO | COMMAND_ACK)).unwrap();
+        assert_eq!(next_command(&commands), Some(AtCommand::Ctrl(CtrlMode::Ack)));
+        assert!(wait_for_event(&events, NavDataEvent::HandshakeDone));
+
+        peer.send_navdata(&packet(3, NAVDATA_DEMO)).unwrap();
+        assert_eq!(next_command(&commands), None);
+        navdata.stop_navdata_listening_thread().unwrap();
+    }
+
+    #[test]
+    fn sequence_reset_starts_a_new_session() {
+        let (mut navdata, peer, _commands) = memory_navdata();
+        let snapshots = navdata.subscribe();
+        let events = navdata.subscribe_events();
+        for seq in 1000..1005 {
+            send_accepted(&peer, &snapshots, &packet(seq, NAVDATA_DEMO));
+        }
+
+        // Late, repeated and late bootstrap packets are discarded
+        peer.send_navdata(&packet(1002, NAVDATA_DEMO)).unwrap();
+        peer.send_navdata(&packet(1004, NAVDATA_DEMO)).unwrap();
+        peer.send_navdata(&packet(1003, NAVDATA_BOOTSTRAP)).unwrap();
+        send_accepted(&peer, &snapshots, &packet(1005, NAVDATA_DEMO));
+        let stats = navdata.get_stats();
+        assert_eq!(stats.packets_received, 6);
+        assert_eq!(stats.out_of_order, 2);
+        assert_eq!(stats.duplicates, 1);
+
+        // The drone rebooted
+        send_accepted(&peer, &snapshots, &packet(1, NAVDATA_BOOTSTRAP));
+        assert!(wait_for_event(&events, NavDataEvent::SessionReset));
+        let stats = navdata.get_stats();
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(stats.out_of_order, 0);
+        navdata.stop_navdata_listening_thread().unwrap();
+    }
+
+    #[test]
+    fn any_sequence_number_starts_a_new_session_after_a_lost_link() {
+        let (mut navdata, peer, _commands) = memory_navdata();
+        navdata.reader().set_link_timeout(time::Duration::from_millis(100));
+        let snapshots = navdata.subscribe();
+        let events = navdata.subscribe_events();
+        send_accepted(&peer, &snapshots, &packet(50, NAVDATA_DEMO));
+        assert!(wait_for_event(&events, NavDataEvent::LinkLost));
+
+        send_accepted(&peer, &snapshots, &packet(40, NAVDATA_DEMO));
+        assert!(wait_for_event(&events, NavDataEvent::SessionReset));
+        assert_eq!(navdata.get_snapshot().unwrap().header.seq_num, 40);
+        navdata.stop_navdata_listening_thread().unwrap();
+    }
+
+    #[test]
+    fn lost_link_is_woken_up_until_navdata_comes_back() {
+        let (mut navdata, peer, _commands) = memory_navdata();
+        navdata.reader().set_link_timeout(time::Duration::from_millis(100));
+        let snapshots = navdata.subscribe();
+        let events = navdata.subscribe_events();
+        send_accepted(&peer, &snapshots, &packet(1, NAVDATA_DEMO));
+        peer.navdata_wake_ups();
+
+        assert!(wait_for_event(&events, NavDataEvent::LinkLost));
+        assert!(navdata.no_navdata());
+        thread::sleep(time::Duration::from_millis(700));
+        assert!(peer.navdata_wake_ups() >= 2);
+
+        send_accepted(&peer, &snapshots, &packet(2, NAVDATA_DEMO));
+        assert!(wait_for_event(&events, NavDataEvent::LinkRestored));
+        assert!(!navdata.no_navdata());
+        // The link is fine again, no more wake-ups
+        peer.navdata_wake_ups();
+        thread::sleep(time::Duration::from_millis(50));
+        assert_eq!(peer.navdata_wake_ups(), 0);
+        navdata.stop_navdata_listening_thread().unwrap();
+    }
+
+    #[test]
+    fn callback_runs_for_accepted_packets_only() {
+        // Registered before the thread starts, so that it sees the first packet
+        let mut navdata = NavData::new();
+        let (seqs, seq_receiver) = mpsc::channel();
+        navdata.on_navdata(move |snapshot| {
+            let _ = seqs.send(snapshot.header.seq_num);
+        }).unwrap();
+        let (transport, peer) = MemoryTransport::new();
+        navdata.start_navdata_listening_thread(Arc::new(transport), false, None);
+
+        let mut corrupt = packet(3, NAVDATA_DEMO);
+        corrupt[4] ^= 0x01;
+        for data in &[
+            packet(1, NAVDATA_DEMO),
+            packet(2, NAVDATA_DEMO),
+            packet(2, NAVDATA_DEMO), // duplicate
+            packet(1, NAVDATA_DEMO), // out of order
+            corrupt,
+            vec![1, 2, 3],
+            packet(3, NAVDATA_DEMO),
+        ] {
+            peer.send_navdata(data).unwrap();
+        }
+        let received = (0..3).map(|_| seq_receiver.recv_timeout(WAIT).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(received, vec![1, 2, 3]);
+        assert!(seq_receiver.try_recv().is_err());
+        let stats = navdata.get_stats();
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.checksum_failures, 1);
+        assert_eq!(stats.parse_errors, 1);
+        navdata.stop_navdata_listening_thread().unwrap();
+    }
+
+    #[test]
+    fn starting_again_stops_the_running_thread() {
+        let (mut navdata, peer, _commands) = memory_navdata();
+        let (transport, _other_peer) = MemoryTransport::new();
+        navdata.start_navdata_listening_thread(Arc::new(transport), false, None);
+
+        // The first thread has ended, its end of the transport is gone
+        assert!(peer.send_navdata(&packet(1, 0)).is_err());
+        navdata.stop_navdata_listening_thread().unwrap();
+    }
+
+    /// A worker which is only fed through handle_datagram
+    fn worker() -> NavDataWorker {
+        let (transport, _peer) = MemoryTransport::new();
+        let now = time::Instant::now();
+        NavDataWorker {
+            stream: Box::new(TransportNavData(Arc::new(transport))),
+            print_error: false,
+            reader: NavDataReader::default(),
+            listeners: Vec::new(),
+            event_listeners: Vec::new(),
+            recorder: None,
+            seq_num: 0,
+            last_packet: now,
+            last_wake_up: now,
+            link_lost: false,
+            commands: None,
+            waiting_for_ack: None,
+        }
+    }
+
+    #[test]
+    fn stats_count_the_gaps_of_the_sequence() {
+        let start = time::Instant::now();
+        let mut stats = NavDataStats::default();
+        let mut last = 0;
+        // 4 packets missing, one every 50 ms
+        for (i, seq) in [1, 2, 3, 6, 7, 10].iter().enumerate() {
+            stats.packet_received(seq - last, start + time::Duration::from_millis(50 * i as u64));
+            last = *seq;
+        }
+        assert_eq!(stats.packets_received, 6);
+        assert_eq!(stats.packets_dropped, 4);
+        assert_eq!(stats.loss_ratio(), 0.4);
+        assert_eq!(stats.rate(), 20.0);
+        assert_eq!(stats.mean_interval, time::Duration::from_millis(50));
+        assert_eq!(stats.jitter, time::Duration::ZERO);
+        assert_eq!(stats.session_start, Some(start));
+    }
+
+    #[test]
+    fn corrupt_packets_are_counted_apart_from_the_lost_ones() {
+        let mut worker = worker();
+        let mut corrupt = packet(4, NAVDATA_DEMO);
+        corrupt[4] ^= 0x01;
+        worker.handle_datagram(&packet(1, NAVDATA_DEMO));
+        worker.handle_datagram(&packet(2, NAVDATA_DEMO));
+        worker.handle_datagram(&corrupt);
+        worker.handle_datagram(&packet(5, NAVDATA_DEMO));
+        worker.handle_datagram(&packet(8, NAVDATA_DEMO));
+
+        let stats = worker.reader.stats();
+        assert_eq!(stats.packets_received, 4);
+        assert_eq!(stats.checksum_failures, 1);
+        // 3, the corrupt 4, 6 and 7 never arrived as valid packets
+        assert_eq!(stats.packets_dropped, 4);
+        assert_eq!(stats.loss_ratio(), 0.5);
+        assert_eq!(stats.parse_errors, 0);
+    }
+
+    #[test]
+    fn flipped_byte_fails_the_checksum() {
+        let mut snapshot = NavDataSnapshot::default();
+        snapshot.header.header = NAVDATA_HEADER;
+        snapshot.header.seq_num = 1;
+        snapshot.demo = Some(DemoData { battery: 80, altitude: 1200, ..DemoData::default() });
+        let mut data = NavDataPacket::from_snapshot(&snapshot).to_bytes();
+        let checksum = parse_navdata(&data).unwrap().checksum;
+
+        // The battery of the demo block, after the header and the block id and size
+        data[24] ^= 0x01;
+        assert_eq!(parse_navdata(&data), Err(NavDataError::WrongChecksum {
+            expected: checksum,
+            computed: checksum + 1,
+        }));
+
+        let mut worker = worker();
+        let (events, event_receiver) = mpsc::channel();
+        worker.event_listeners.push(events);
+        worker.handle_datagram(&data);
+        let stats = worker.reader.stats();
+        assert_eq!(stats.checksum_failures, 1);
+        assert_eq!(stats.packets_received, 0);
+        assert!(worker.reader.snapshot().is_none());
+        assert!(matches!(event_receiver.try_recv(),
+                         Ok(NavDataEvent::PacketDropped(NavDataError::WrongChecksum { .. }))));
+    }
+
+    /// The 16 bytes of a navdata header
+    fn header_bytes(drone_state: u32, seq_num: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&NAVDATA_HEADER.to_le_bytes());
+        data.extend_from_slice(&drone_state.to_le_bytes());
+        data.extend_from_slice(&seq_num.to_le_bytes());
+        data.extend_from_slice(&0_u32.to_le_bytes());
+        data
+    }
+
+    /// Appends an option block with its id and size
+    fn push_option(data: &mut Vec<u8>, id: u16, payload: &[u8]) {
+        data.extend_from_slice(&id.to_le_bytes());
+        data.extend_from_slice(&(payload.len() as u16 + 4).to_le_bytes());
+        data.extend_from_slice(payload);
+    }
+
+    /// Appends the checksum block of everything before it
+    fn push_checksum(data: &mut Vec<u8>) -> u32 {
+        let checksum = data.iter().map(|b| u32::from(*b)).sum();
+        push_option(data, NAVDATA_CHECKSUM_ID, &u32::to_le_bytes(checksum));
+        checksum
+    }
+
+    #[test]
+    fn hand_built_demo_packet_is_decoded() {
+        let mut data = header_bytes(0x8000_0401, 1234);
+        let mut demo = Vec::new();
+        demo.extend_from_slice(&(4_u32 << 16).to_le_bytes()); // hovering
+        demo.extend_from_slice(&87_u32.to_le_bytes());
+        demo.extend_from_slice(&1500_f32.to_le_bytes()); // theta
+        demo.extend_from_slice(&(-2500_f32).to_le_bytes()); // phi
+        demo.extend_from_slice(&90000_f32.to_le_bytes()); // psi
+        demo.extend_from_slice(&1200_i32.to_le_bytes()); // altitude
+        demo.extend_from_slice(&100_f32.to_le_bytes()); // vx
+        demo.extend_from_slice(&(-50_f32).to_le_bytes()); // vy
+        demo.extend_from_slice(&0_f32.to_le_bytes()); // vz
+        demo.resize(144, 0);
+        push_option(&mut data, DemoData::ID, &demo);
+        assert_eq!(data.len(), 16 + 148);
+        let checksum = push_checksum(&mut data);
+
+        let packet = parse_navdata(&data).unwrap();
+        assert_eq!(packet.header.header, NAVDATA_HEADER);
+        assert_eq!(packet.header.seq_num, 1234);
+        assert!(packet.header.drone_state.flying());
+        assert!(packet.header.drone_state.navdata_demo());
+        assert!(packet.header.drone_state.emergency());
+        assert_eq!(packet.checksum, checksum);
+        let snapshot = packet.snapshot();
+        let demo = snapshot.demo.unwrap();
+        assert_eq!(demo.control_state(), ControlState::Hovering);
+        assert_eq!(demo.battery, 87);
+        assert_eq!((demo.theta, demo.phi, demo.psi), (1500.0, -2500.0, 90000.0));
+        assert_eq!(demo.altitude, 1200);
+        assert_eq!((demo.vx, demo.vy, demo.vz), (100.0, -50.0, 0.0));
+        // The encoder writes the same bytes back
+        assert_eq!(packet.to_bytes(), data);
+    }
+
+    #[test]
+    fn packets_shorter_than_a_header_are_truncated() {
+        let data = header_bytes(0, 1);
+        assert_eq!(parse_navdata(&[]), Err(NavDataError::Truncated));
+        assert_eq!(parse_navdata(&data[..3]), Err(NavDataError::Truncated));
+        assert_eq!(parse_navdata(&data[..15]), Err(NavDataError::Truncated));
+        // A whole header without any option block (not even the checksum)
+        assert_eq!(parse_navdata(&data), Err(NavDataError::Truncated));
+    }
+
+    #[test]
+    fn wrong_header_is_rejected() {
+        let mut data = header_bytes(0, 1);
+        data[0] = 0;
+        push_checksum(&mut data);
+        assert_eq!(parse_navdata(&data), Err(NavDataError::WrongHeader(0x5566_7700)));
+    }
+
+    #[test]
+    fn option_sizes_are_checked() {
+        // Smaller than the option header
+        let mut data = header_bytes(0, 1);
+        data.extend_from_slice(&[100, 0, 3, 0]);
+        push_checksum(&mut data);
+        assert_eq!(parse_navdata(&data), Err(NavDataError::WrongOptionSize { id: 100, size: 3 }));
+
+        // The demo block always has 148 bytes
+        let mut data = header_bytes(0, 1);
+        push_option(&mut data, DemoData::ID, &[0; 148]);
+        push_checksum(&mut data);
+        assert_eq!(parse_navdata(&data),
+                   Err(NavDataError::WrongOptionSize { id: DemoData::ID, size: 152 }));
+
+        // Running past the end of the datagram
+        let mut data = header_bytes(0, 1);
+        data.extend_from_slice(&[100, 0, 64, 0, 1, 2, 3, 4]);
+        assert_eq!(parse_navdata(&data), Err(NavDataError::Truncated));
+
+        // The checksum block is too short for its value
+        let mut data = header_bytes(0, 1);
+        push_option(&mut data, NAVDATA_CHECKSUM_ID, &[0, 0]);
+        assert_eq!(parse_navdata(&data),
+                   Err(NavDataError::WrongOptionSize { id: NAVDATA_CHECKSUM_ID, size: 6 }));
+    }
+
+    #[test]
+    fn unknown_and_short_blocks_are_kept_as_unknown() {
+        let mut data = header_bytes(0, 1);
+        push_option(&mut data, 4242, &[1, 2, 3]);
+        // The time block holds a u32, this one is too short
+        push_option(&mut data, Time::ID, &[5, 6]);
+        push_option(&mut data, Time::ID, &42_u32.to_le_bytes());
+        push_checksum(&mut data);
+
+        let packet = parse_navdata(&data).unwrap();
+        assert_eq!(packet.options, vec![
+            NavDataOption::Unknown { id: 4242, data: vec![1, 2, 3] },
+            NavDataOption::Unknown { id: Time::ID, data: vec![5, 6] },
+            NavDataOption::Time(Time { time: 42 }),
+        ]);
     }
 }
@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::collections::vec_deque;
+use std::time::{Duration, Instant};
+
+use crate::navdata_options::NavDataSnapshot;
+
+/// Default number of packets kept in the navdata history
+pub const DEFAULT_NAVDATA_HISTORY_SIZE: usize = 1000;
+
+/// A navdata packet together with the time it was received by this computer
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavDataRecord {
+    pub received: Instant,
+    pub snapshot: NavDataSnapshot,
+}
+
+/// The last few navdata packets, oldest first. When the history is full the
+/// oldest packet is dropped for every new one.
+/// ```
+/// use parrot_ar_drone::*;
+/// use std::time::{Duration, Instant};
+///
+/// let mut history = NavDataHistory::new(2);
+/// for altitude in &[100, 200, 300] {
+///     let mut snapshot = NavDataSnapshot::default();
+///     snapshot.demo = Some(DemoData { altitude: *altitude, ..DemoData::default() });
+///     history.push(NavDataRecord { received: Instant::now(), snapshot });
+/// }
+///
+/// let altitudes = history.values_in_last(Duration::from_secs(5), |s| s.demo.map(|d| d.altitude));
+/// assert_eq!(altitudes.iter().map(|(_, a)| *a).collect::<Vec<_>>(), vec![200, 300]);
+/// assert_eq!(history.mean_in_last(Duration::from_secs(5), |s| s.demo.map(|d| d.altitude as f32)), Some(250.0));
+/// ```
+#[derive(Debug, Clone)]
+pub struct NavDataHistory {
+    capacity: usize,
+    records: VecDeque<NavDataRecord>,
+}
+
+impl NavDataHistory {
+    /// Returns an empty history which keeps at most `capacity` packets
+    pub fn new(capacity: usize) -> NavDataHistory {
+        NavDataHistory {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the number of packets kept, dropping the oldest ones if needed
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.records.len() > capacity {
+            self.records.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub fn push(&mut self, record: NavDataRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Every packet in the history, oldest first
+    pub fn iter(&self) -> vec_deque::Iter<'_, NavDataRecord> {
+        self.records.iter()
+    }
+
+    /// The most recent packet
+    pub fn latest(&self) -> Option<&NavDataRecord> {
+        self.records.back()
+    }
+
+    /// The packets received at or after `since`, oldest first
+    pub fn since(&self, since: Instant) -> impl Iterator<Item = &NavDataRecord> {
+        self.records.iter().filter(move |r| r.received >= since)
+    }
+
+    /// The packets received in the last `window` (e.g. the last 5 seconds)
+    pub fn in_last(&self, window: Duration) -> impl Iterator<Item = &NavDataRecord> {
+        let now = Instant::now();
+        self.records.iter().filter(move |r| now.duration_since(r.received) <= window)
+    }
+
+    /// Extracts a value from every packet received at or after `since`.
+    /// Packets for which `value` returns None are skipped.
+    pub fn values_since<T, F>(&self, since: Instant, value: F) -> Vec<(Instant, T)>
+        where F: Fn(&NavDataSnapshot) -> Option<T> {
+        self.since(since)
+            .filter_map(|r| value(&r.snapshot).map(|v| (r.received, v)))
+            .collect()
+    }
+
+    /// Extracts a value from every packet received in the last `window`.
+    /// Packets for which `value` returns None are skipped.
+    pub fn values_in_last<T, F>(&self, window: Duration, value: F) -> Vec<(Instant, T)>
+        where F: Fn(&NavDataSnapshot) -> Option<T> {
+        self.in_last(window)
+            .filter_map(|r| value(&r.snapshot).map(|v| (r.received, v)))
+            .collect()
+    }
+
+    /// The mean of a value over the packets received at or after `since`,
+    /// None if no packet had the value.
+    pub fn mean_since<F>(&self, since: Instant, value: F) -> Option<f32>
+        where F: Fn(&NavDataSnapshot) -> Option<f32> {
+        mean(self.values_since(since, value))
+    }
+
+    /// The mean of a value over the packets received in the last `window`,
+    /// None if no packet had the value.
+    pub fn mean_in_last<F>(&self, window: Duration, value: F) -> Option<f32>
+        where F: Fn(&NavDataSnapshot) -> Option<f32> {
+        mean(self.values_in_last(window, value))
+    }
+}
+
+impl Default for NavDataHistory {
+    fn default() -> NavDataHistory {
+        NavDataHistory::new(DEFAULT_NAVDATA_HISTORY_SIZE)
+    }
+}
+
+fn mean(values: Vec<(Instant, f32)>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    let count = values.len() as f32;
+    Some(values.into_iter().map(|(_, v)| v).sum::<f32>() / count)
+}
@@ -0,0 +1,1063 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::ops::{BitAnd, BitOr, BitOrAssign, Not};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::navdata::NavDataValue;
+
+/// A value that can appear inside a navdata option block. It knows how to read
+/// itself from the packet, how to write itself back and how to put itself
+/// into the navdata lookup.
+pub trait NavDataField: Sized {
+    fn read_from(crs: &mut Cursor<&[u8]>) -> io::Result<Self>;
+    fn write_to(&self, out: &mut Vec<u8>);
+    fn insert_into(&self, key: String, options_map: &mut HashMap<String, NavDataValue>);
+}
+
+macro_rules! navdata_field {
+    ($ty:ty, $read:expr, $variant:ident, $conv:ty) => {
+        impl NavDataField for $ty {
+            fn read_from(crs: &mut Cursor<&[u8]>) -> io::Result<$ty> {
+                $read(crs)
+            }
+
+            fn write_to(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn insert_into(&self, key: String, options_map: &mut HashMap<String, NavDataValue>) {
+                options_map.insert(key, NavDataValue::$variant(<$conv>::from(*self)));
+            }
+        }
+    };
+}
+
+navdata_field!(u8, |c: &mut Cursor<&[u8]>| c.read_u8(), Uint, u32);
+navdata_field!(u16, |c: &mut Cursor<&[u8]>| c.read_u16::<LittleEndian>(), Uint, u32);
+navdata_field!(i16, |c: &mut Cursor<&[u8]>| c.read_i16::<LittleEndian>(), Int, i32);
+navdata_field!(u32, |c: &mut Cursor<&[u8]>| c.read_u32::<LittleEndian>(), Uint, u32);
+navdata_field!(i32, |c: &mut Cursor<&[u8]>| c.read_i32::<LittleEndian>(), Int, i32);
+navdata_field!(f32, |c: &mut Cursor<&[u8]>| c.read_f32::<LittleEndian>(), Float, f32);
+navdata_field!(f64, |c: &mut Cursor<&[u8]>| c.read_f64::<LittleEndian>(), Double, f64);
+
+/// Arrays are stored element by element, with the index appended to the key
+/// (e.g. `vision_of_of_dx_0`, `vision_of_of_dx_1`...).
+impl<T: NavDataField + Default + Copy, const N: usize> NavDataField for [T; N] {
+    fn read_from(crs: &mut Cursor<&[u8]>) -> io::Result<[T; N]> {
+        let mut values = [T::default(); N];
+        for value in values.iter_mut() {
+            *value = T::read_from(crs)?;
+        }
+        Ok(values)
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        for value in self.iter() {
+            value.write_to(out);
+        }
+    }
+
+    fn insert_into(&self, key: String, options_map: &mut HashMap<String, NavDataValue>) {
+        for (i, value) in self.iter().enumerate() {
+            value.insert_into(format!("{}_{}", key, i), options_map);
+        }
+    }
+}
+
+/// Defines a navdata option block: its id, the prefix of its keys in the
+/// navdata lookup and its fields in the order they are sent by the drone.
+macro_rules! navdata_option {
+    ($(#[$meta:meta])* $name:ident = $id:expr, $prefix:expr, {
+        $($(#[$field_meta:meta])* $field:ident: $ty:ty),* $(,)?
+    }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Default, PartialEq)]
+        pub struct $name {
+            $($(#[$field_meta])* pub $field: $ty,)*
+        }
+
+        impl $name {
+            /// The id of the option block in the navdata packet
+            pub const ID: u16 = $id;
+            /// The prefix of the keys of this option block in the navdata lookup
+            pub const PREFIX: &'static str = $prefix;
+
+            pub fn read_from(crs: &mut Cursor<&[u8]>) -> io::Result<$name> {
+                Ok($name {
+                    $($field: <$ty as NavDataField>::read_from(crs)?,)*
+                })
+            }
+
+            /// Writes the fields (without the id and size of the block)
+            pub fn write_to(&self, out: &mut Vec<u8>) {
+                $(self.$field.write_to(out);)*
+            }
+
+            pub fn insert_into(&self, options_map: &mut HashMap<String, NavDataValue>) {
+                $(self.$field.insert_into(
+                        format!("{}_{}", Self::PREFIX, stringify!($field)),
+                        options_map);)*
+            }
+        }
+    };
+}
+
+navdata_option!(
+    /// Time of the drone: upper 21 bits are seconds, lower 11 bits are microseconds
+    Time = 1, "time", {
+    time: u32,
+});
+
+navdata_option!(
+    /// Raw sensor measurements
+    RawMeasures = 2, "raw_measures", {
+    raw_accs: [u16; 3],
+    raw_gyros: [i16; 3],
+    raw_gyros_110: [i16; 2],
+    vbat_raw: u32,
+    us_debut_echo: u16,
+    us_fin_echo: u16,
+    us_association_echo: u16,
+    us_distance_echo: u16,
+    us_courbe_temps: u16,
+    us_courbe_valeur: u16,
+    us_courbe_ref: u16,
+    flag_echo_ini: u16,
+    nb_echo: u16,
+    sum_echo: u32,
+    alt_temp_raw: i32,
+    gradient: i16,
+});
+
+navdata_option!(
+    /// Sensor measurements converted to physical units
+    PhysMeasures = 3, "phys_measures", {
+    accs_temp: f32,
+    gyro_temp: u16,
+    phys_accs: [f32; 3],
+    phys_gyros: [f32; 3],
+    alim3v3: u32,
+    vref_epson: u32,
+    vref_idg: u32,
+});
+
+navdata_option!(
+    GyrosOffsets = 4, "gyros_offsets", {
+    offset_g: [f32; 3],
+});
+
+navdata_option!(
+    EulerAngles = 5, "euler_angles", {
+    theta_a: f32,
+    phi_a: f32,
+});
+
+navdata_option!(
+    References = 6, "references", {
+    ref_theta: i32,
+    ref_phi: i32,
+    ref_theta_i: i32,
+    ref_phi_i: i32,
+    ref_pitch: i32,
+    ref_roll: i32,
+    ref_yaw: i32,
+    ref_psi: i32,
+    vx_ref: f32,
+    vy_ref: f32,
+    theta_mod: f32,
+    phi_mod: f32,
+    k_v_x: f32,
+    k_v_y: f32,
+    k_mode: u32,
+    ui_time: f32,
+    ui_theta: f32,
+    ui_phi: f32,
+    ui_psi: f32,
+    ui_psi_accuracy: f32,
+    ui_seq: i32,
+});
+
+navdata_option!(
+    Trims = 7, "trims", {
+    angular_rates_trim_r: f32,
+    euler_angles_trim_theta: f32,
+    euler_angles_trim_phi: f32,
+});
+
+navdata_option!(
+    RcReferences = 8, "rc_references", {
+    rc_ref_pitch: i32,
+    rc_ref_roll: i32,
+    rc_ref_yaw: i32,
+    rc_ref_gaz: i32,
+    rc_ref_ag: i32,
+});
+
+navdata_option!(
+    /// Motor commands
+    Pwm = 9, "pwm", {
+    motors: [u8; 4],
+    sat_motors: [u8; 4],
+    gaz_feed_forward: f32,
+    gaz_altitude: f32,
+    altitude_integral: f32,
+    vz_ref: f32,
+    u_pitch: i32,
+    u_roll: i32,
+    u_yaw: i32,
+    yaw_u_i: f32,
+    u_pitch_planif: i32,
+    u_roll_planif: i32,
+    u_yaw_planif: i32,
+    u_gaz_planif: f32,
+    current_motors: [u16; 4],
+    altitude_prop: f32,
+    altitude_der: f32,
+});
+
+navdata_option!(
+    Altitude = 10, "altitude", {
+    altitude_vision: i32,
+    altitude_vz: f32,
+    altitude_ref: i32,
+    altitude_raw: i32,
+    obs_acc_z: f32,
+    obs_alt: f32,
+    obs_x: [f32; 3],
+    obs_state: u32,
+    est_vb: [f32; 2],
+    est_state: u32,
+});
+
+navdata_option!(
+    VisionRaw = 11, "vision_raw", {
+    vision_tx_raw: f32,
+    vision_ty_raw: f32,
+    vision_tz_raw: f32,
+});
+
+navdata_option!(
+    /// Optical flow
+    VisionOf = 12, "vision_of", {
+    of_dx: [f32; 5],
+    of_dy: [f32; 5],
+});
+
+navdata_option!(
+    Vision = 13, "vision", {
+    vision_state: u32,
+    vision_misc: i32,
+    vision_phi_trim: f32,
+    vision_phi_ref_prop: f32,
+    vision_theta_trim: f32,
+    vision_theta_ref_prop: f32,
+    new_raw_picture: i32,
+    theta_capture: f32,
+    phi_capture: f32,
+    psi_capture: f32,
+    altitude_capture: i32,
+    time_capture: u32,
+    body_v: [f32; 3],
+    delta_phi: f32,
+    delta_theta: f32,
+    delta_psi: f32,
+    gold_defined: u32,
+    gold_reset: u32,
+    gold_x: f32,
+    gold_y: f32,
+});
+
+navdata_option!(
+    VisionPerf = 14, "vision_perf", {
+    time_szo: f32,
+    time_corners: f32,
+    time_compute: f32,
+    time_tracking: f32,
+    time_trans: f32,
+    time_update: f32,
+    time_custom: [f32; 20],
+});
+
+navdata_option!(
+    TrackersSend = 15, "trackers_send", {
+    locked: [i32; 30],
+    /// Screen points (x, y) of the trackers
+    point: [[i32; 2]; 30],
+});
+
+navdata_option!(
+    /// Tags detected by the cameras (at most 4)
+    VisionDetect = 16, "vision_detect", {
+    nb_detected: u32,
+    tag_type: [u32; 4],
+    xc: [u32; 4],
+    yc: [u32; 4],
+    width: [u32; 4],
+    height: [u32; 4],
+    dist: [u32; 4],
+    orientation_angle: [f32; 4],
+    rotation: [[f32; 9]; 4],
+    translation: [[f32; 3]; 4],
+    camera_source: [u32; 4],
+});
+
+navdata_option!(
+    Watchdog = 17, "watchdog", {
+    watchdog: i32,
+});
+
+navdata_option!(
+    AdcDataFrame = 18, "adc_data_frame", {
+    version: u32,
+    data_frame: [u8; 32],
+});
+
+navdata_option!(
+    VideoStream = 19, "video_stream", {
+    quant: u8,
+    frame_size: u32,
+    frame_number: u32,
+    atcmd_ref_seq: u32,
+    atcmd_mean_ref_gap: u32,
+    atcmd_var_ref_gap: f32,
+    atcmd_ref_quality: u32,
+    out_bitrate: u32,
+    desired_bitrate: u32,
+    data: [i32; 5],
+    tcp_queue_level: u32,
+    fifo_queue_level: u32,
+});
+
+navdata_option!(
+    Games = 20, "games", {
+    double_tap_counter: u32,
+    finish_line_counter: u32,
+});
+
+navdata_option!(
+    PressureRaw = 21, "pressure_raw", {
+    up: i32,
+    ut: i16,
+    temperature_meas: i32,
+    pression_meas: i32,
+});
+
+navdata_option!(
+    Magneto = 22, "magneto", {
+    mx: i16,
+    my: i16,
+    mz: i16,
+    magneto_raw: [f32; 3],
+    magneto_rectified: [f32; 3],
+    magneto_offset: [f32; 3],
+    heading_unwrapped: f32,
+    heading_gyro_unwrapped: f32,
+    heading_fusion_unwrapped: f32,
+    magneto_calibration_ok: u8,
+    magneto_state: u32,
+    magneto_radius: f32,
+    error_mean: f32,
+    error_var: f32,
+});
+
+navdata_option!(
+    WindSpeed = 23, "wind_speed", {
+    wind_speed: f32,
+    wind_angle: f32,
+    wind_compensation_theta: f32,
+    wind_compensation_phi: f32,
+    state: [f32; 6],
+    magneto_debug: [f32; 3],
+});
+
+navdata_option!(
+    KalmanPressure = 24, "kalman_pressure", {
+    offset_pressure: f32,
+    est_z: f32,
+    est_zdot: f32,
+    est_bias_pwm: f32,
+    est_biais_pression: f32,
+    offset_us: f32,
+    prediction_us: f32,
+    cov_alt: f32,
+    cov_pwm: f32,
+    cov_vitesse: f32,
+    bool_effet_sol: u32,
+    somme_inno: f32,
+    flag_rejet_us: u32,
+    u_multisinus: f32,
+    gaz_altitude: f32,
+    flag_multisinus: u32,
+    flag_multisinus_debut: u32,
+});
+
+navdata_option!(
+    HdVideoStream = 25, "hdvideo_stream", {
+    hdvideo_state: u32,
+    storage_fifo_nb_packets: u32,
+    storage_fifo_size: u32,
+    usbkey_size: u32,
+    usbkey_freespace: u32,
+    frame_number: u32,
+    usbkey_remaining_time: u32,
+});
+
+navdata_option!(
+    Wifi = 26, "wifi", {
+    link_quality: u32,
+});
+
+navdata_option!(
+    /// Sent only when the flight recorder (GPS) is plugged in
+    Gps = 27, "gps", {
+    latitude: f64,
+    longitude: f64,
+    elevation: f64,
+    hdop: f64,
+    data_available: i32,
+    zero_validated: i32,
+    wpt_validated: i32,
+    lat0: f64,
+    long0: f64,
+    lat_fused: f64,
+    long_fused: f64,
+    gps_state: u32,
+    x_traj: f32,
+    x_ref: f32,
+    y_traj: f32,
+    y_ref: f32,
+    theta_p: f32,
+    phi_p: f32,
+    theta_i: f32,
+    phi_i: f32,
+    theta_d: f32,
+    phi_d: f32,
+    vdop: f64,
+    pdop: f64,
+    speed: f32,
+    last_frame_timestamp: u32,
+    degree: f32,
+    degree_magnetic: f32,
+    ehpe: f32,
+    ehve: f32,
+    c_n0: f32,
+    nbsat: u32,
+    /// Satellite number and C/N0 of each channel
+    channels: [[u8; 2]; 12],
+    is_gps_plugged: i32,
+    ephemeris_status: u32,
+    vx_traj: f32,
+    vy_traj: f32,
+    firmware_status: u32,
+});
+
+/// The state of the drone, as sent in the header of every navdata packet.
+/// ```
+/// use parrot_ar_drone::DroneState;
+///
+/// let state = DroneState(1 | 1 << 31);
+/// assert!(state.flying());
+/// assert!(state.emergency());
+/// assert!(!state.battery_low());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DroneState(pub u32);
+
+impl DroneState {
+    /// The raw bitfield
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Whether the given bit (0 - 31) of the state is set
+    pub fn bit(&self, n: u32) -> bool {
+        self.0 >> n & 1 == 1
+    }
+
+    /// Drone is flying (bit 0)
+    pub fn flying(&self) -> bool {
+        self.bit(0)
+    }
+
+    /// Video is enabled (bit 1)
+    pub fn video_enabled(&self) -> bool {
+        self.bit(1)
+    }
+
+    /// Vision is enabled (bit 2)
+    pub fn vision_enabled(&self) -> bool {
+        self.bit(2)
+    }
+
+    /// Control algorithm: false means euler angles control, true means angular speed control (bit 3)
+    pub fn angular_speed_control(&self) -> bool {
+        self.bit(3)
+    }
+
+    /// Altitude control algorithm is active (bit 4)
+    pub fn altitude_control(&self) -> bool {
+        self.bit(4)
+    }
+
+    /// Start button state (bit 5)
+    pub fn user_feedback_start(&self) -> bool {
+        self.bit(5)
+    }
+
+    /// Control command was acknowledged (ACK) (bit 6)
+    pub fn command_ack(&self) -> bool {
+        self.bit(6)
+    }
+
+    /// Camera is ready (bit 7)
+    pub fn camera_ready(&self) -> bool {
+        self.bit(7)
+    }
+
+    /// Travelling is enabled (bit 8)
+    pub fn travelling(&self) -> bool {
+        self.bit(8)
+    }
+
+    /// USB key is ready (bit 9)
+    pub fn usb_ready(&self) -> bool {
+        self.bit(9)
+    }
+
+    /// Only the demo navdata is sent (bit 10)
+    pub fn navdata_demo(&self) -> bool {
+        self.bit(10)
+    }
+
+    /// No navdata options were requested yet (bootstrap mode) (bit 11)
+    pub fn navdata_bootstrap(&self) -> bool {
+        self.bit(11)
+    }
+
+    /// There is a problem with the motors (bit 12)
+    pub fn motors_problem(&self) -> bool {
+        self.bit(12)
+    }
+
+    /// Communication with the drone is lost (bit 13)
+    pub fn communication_lost(&self) -> bool {
+        self.bit(13)
+    }
+
+    /// Software fault detected (bit 14)
+    pub fn software_fault(&self) -> bool {
+        self.bit(14)
+    }
+
+    /// Battery is too low to fly (bit 15)
+    pub fn battery_low(&self) -> bool {
+        self.bit(15)
+    }
+
+    /// User emergency landing is on (bit 16)
+    pub fn user_emergency(&self) -> bool {
+        self.bit(16)
+    }
+
+    /// Timer elapsed (bit 17)
+    pub fn timer_elapsed(&self) -> bool {
+        self.bit(17)
+    }
+
+    /// Magnetometer needs to be calibrated (bit 18)
+    pub fn magnetometer_calibration_needed(&self) -> bool {
+        self.bit(18)
+    }
+
+    /// Angles are out of range (bit 19)
+    pub fn angles_out_of_range(&self) -> bool {
+        self.bit(19)
+    }
+
+    /// There is too much wind to fly (bit 20)
+    pub fn too_much_wind(&self) -> bool {
+        self.bit(20)
+    }
+
+    /// Ultrasonic sensor is deaf (bit 21)
+    pub fn ultrasound_problem(&self) -> bool {
+        self.bit(21)
+    }
+
+    /// Cutout system detected (bit 22)
+    pub fn cutout(&self) -> bool {
+        self.bit(22)
+    }
+
+    /// PIC version number is OK (bit 23)
+    pub fn pic_version_ok(&self) -> bool {
+        self.bit(23)
+    }
+
+    /// ATCodec thread is on (bit 24)
+    pub fn atcodec_thread_on(&self) -> bool {
+        self.bit(24)
+    }
+
+    /// Navdata thread is on (bit 25)
+    pub fn navdata_thread_on(&self) -> bool {
+        self.bit(25)
+    }
+
+    /// Video thread is on (bit 26)
+    pub fn video_thread_on(&self) -> bool {
+        self.bit(26)
+    }
+
+    /// Acquisition thread is on (bit 27)
+    pub fn acquisition_thread_on(&self) -> bool {
+        self.bit(27)
+    }
+
+    /// Control execution was delayed by more than 5 ms (bit 28)
+    pub fn ctrl_watchdog(&self) -> bool {
+        self.bit(28)
+    }
+
+    /// Uart2 (ADC) communication problem (bit 29)
+    pub fn adc_watchdog(&self) -> bool {
+        self.bit(29)
+    }
+
+    /// Communication problem (no command received for a while) (bit 30)
+    pub fn com_watchdog(&self) -> bool {
+        self.bit(30)
+    }
+
+    /// Drone is in emergency mode (bit 31)
+    pub fn emergency(&self) -> bool {
+        self.bit(31)
+    }
+}
+
+/// The header of a navdata packet
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Header {
+    /// Should always be 0x55667788
+    pub header: u32,
+    pub drone_state: DroneState,
+    /// Sequence number of the packet, incremented by the drone for every packet
+    pub seq_num: u32,
+    /// Whether the vision was used for computing the data
+    pub vision_flag: u32,
+}
+
+impl Header {
+    pub fn read_from(crs: &mut Cursor<&[u8]>) -> io::Result<Header> {
+        Ok(Header {
+            header: crs.read_u32::<LittleEndian>()?,
+            drone_state: DroneState(crs.read_u32::<LittleEndian>()?),
+            seq_num: crs.read_u32::<LittleEndian>()?,
+            vision_flag: crs.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        self.header.write_to(out);
+        self.drone_state.bits().write_to(out);
+        self.seq_num.write_to(out);
+        self.vision_flag.write_to(out);
+    }
+
+    pub fn insert_into(&self, options_map: &mut HashMap<String, NavDataValue>) {
+        options_map.insert(String::from("header_header"), NavDataValue::Uint(self.header));
+        options_map.insert(String::from("header_seq_num"), NavDataValue::Uint(self.seq_num));
+        options_map.insert(String::from("header_drone_state"), NavDataValue::Uint(self.drone_state.bits()));
+        options_map.insert(String::from("header_flag"), NavDataValue::Uint(self.vision_flag));
+    }
+}
+
+/// The major control state of the drone (upper 16 bits of the demo
+/// `ctrl_state` field).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlState {
+    Default,
+    Init,
+    Landed,
+    Flying,
+    Hovering,
+    Test,
+    TransTakeoff,
+    TransGotofix,
+    TransLanding,
+    TransLooping,
+    Unknown(u32),
+}
+
+/// The demo option block (id 0), which is the only block sent in demo mode
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DemoData {
+    /// Major control state in the upper 16 bits, minor state in the lower 16 bits
+    pub ctrl_state: u32,
+    /// Battery in percent
+    pub battery: u32,
+    /// Pitch in milli-degrees
+    pub theta: f32,
+    /// Roll in milli-degrees
+    pub phi: f32,
+    /// Yaw in milli-degrees
+    pub psi: f32,
+    /// Altitude in millimeters
+    pub altitude: i32,
+    /// Estimated speeds in mm/s
+    pub vx: f32,
+    pub vy: f32,
+    pub vz: f32,
+    pub num_frames: u32,
+    pub detection_camera_rot: [f32; 9],
+    pub detection_camera_trans: [f32; 3],
+    pub detection_tag_index: u32,
+    pub detection_camera_type: u32,
+    pub drone_camera_rot: [f32; 9],
+    pub drone_camera_trans: [f32; 3],
+}
+
+impl DemoData {
+    /// The id of the option block in the navdata packet
+    pub const ID: u16 = 0;
+
+    /// The major control state of the drone
+    pub fn control_state(&self) -> ControlState {
+        match self.ctrl_state >> 16 {
+            0 => ControlState::Default,
+            1 => ControlState::Init,
+            2 => ControlState::Landed,
+            3 => ControlState::Flying,
+            4 => ControlState::Hovering,
+            5 => ControlState::Test,
+            6 => ControlState::TransTakeoff,
+            7 => ControlState::TransGotofix,
+            8 => ControlState::TransLanding,
+            9 => ControlState::TransLooping,
+            s => ControlState::Unknown(s),
+        }
+    }
+
+    pub fn read_from(crs: &mut Cursor<&[u8]>) -> io::Result<DemoData> {
+        Ok(DemoData {
+            ctrl_state: u32::read_from(crs)?,
+            battery: u32::read_from(crs)?,
+            theta: f32::read_from(crs)?,
+            phi: f32::read_from(crs)?,
+            psi: f32::read_from(crs)?,
+            altitude: i32::read_from(crs)?,
+            vx: f32::read_from(crs)?,
+            vy: f32::read_from(crs)?,
+            vz: f32::read_from(crs)?,
+            num_frames: u32::read_from(crs)?,
+            detection_camera_rot: <[f32; 9]>::read_from(crs)?,
+            detection_camera_trans: <[f32; 3]>::read_from(crs)?,
+            detection_tag_index: u32::read_from(crs)?,
+            detection_camera_type: u32::read_from(crs)?,
+            drone_camera_rot: <[f32; 9]>::read_from(crs)?,
+            drone_camera_trans: <[f32; 3]>::read_from(crs)?,
+        })
+    }
+
+    /// Writes the fields (without the id and size of the block)
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        self.ctrl_state.write_to(out);
+        self.battery.write_to(out);
+        self.theta.write_to(out);
+        self.phi.write_to(out);
+        self.psi.write_to(out);
+        self.altitude.write_to(out);
+        self.vx.write_to(out);
+        self.vy.write_to(out);
+        self.vz.write_to(out);
+        self.num_frames.write_to(out);
+        self.detection_camera_rot.write_to(out);
+        self.detection_camera_trans.write_to(out);
+        self.detection_tag_index.write_to(out);
+        self.detection_camera_type.write_to(out);
+        self.drone_camera_rot.write_to(out);
+        self.drone_camera_trans.write_to(out);
+    }
+
+    /// Uses the keys the demo values always had (the altitude is in
+    /// centimeters here). The `demo_*` state flags are taken from the major
+    /// control state.
+    pub fn insert_into(&self, options_map: &mut HashMap<String, NavDataValue>) {
+        // One flag per major control state, only the current one is set
+        let state = self.control_state();
+        for (name, flag_state) in &[
+            ("demo_default", ControlState::Default),
+            ("demo_init", ControlState::Init),
+            ("demo_landed", ControlState::Landed),
+            ("demo_flying", ControlState::Flying),
+            ("demo_hovering", ControlState::Hovering),
+            ("demo_test", ControlState::Test),
+            ("demo_trans_takeoff", ControlState::TransTakeoff),
+            ("demo_trans_gofix", ControlState::TransGotofix),
+            ("demo_trans_landing", ControlState::TransLanding),
+            ("demo_trans_looping", ControlState::TransLooping),
+        ] {
+            options_map.insert(String::from(*name), NavDataValue::Bool(state == *flag_state));
+        }
+
+        options_map.insert(String::from("demo_battery"),NavDataValue::Uint(self.battery));
+
+        options_map.insert(String::from("demo_theta"),NavDataValue::Float(self.theta));
+        options_map.insert(String::from("demo_phi"),NavDataValue::Float(self.phi));
+        options_map.insert(String::from("demo_psi"),NavDataValue::Float(self.psi));
+
+        options_map.insert(String::from("demo_altitude"),NavDataValue::Int(self.altitude / 10));
+
+        options_map.insert(String::from("demo_vx"),NavDataValue::Float(self.vx));
+        options_map.insert(String::from("demo_vy"),NavDataValue::Float(self.vy));
+        options_map.insert(String::from("demo_vz"),NavDataValue::Float(self.vz));
+
+        options_map.insert(String::from("demo_num_frames"),NavDataValue::Uint(self.num_frames));
+
+        self.detection_camera_rot.insert_into(String::from("demo_det_cam_rot"), options_map);
+        self.detection_camera_trans.insert_into(String::from("demo_det_cam_trans"), options_map);
+        options_map.insert(String::from("demo_detection_tag_index"),NavDataValue::Uint(self.detection_tag_index));
+        options_map.insert(String::from("demo_detection_tag_type"),NavDataValue::Uint(self.detection_camera_type));
+        self.drone_camera_rot.insert_into(String::from("demo_cam_rot"), options_map);
+        self.drone_camera_trans.insert_into(String::from("demo_cam_trans"), options_map);
+    }
+}
+
+/// A set of navdata option blocks, used as the `general:navdata_options`
+/// mask. Every option block id is a bit of the mask.
+/// ```
+/// use parrot_ar_drone::NavDataOptions;
+///
+/// let options = NavDataOptions::DEMO | NavDataOptions::VISION_DETECT | NavDataOptions::MAGNETO;
+/// assert!(options.contains(NavDataOptions::MAGNETO));
+/// assert!(!options.contains(NavDataOptions::GPS));
+/// assert_eq!(options.bits(), 1 | 1 << 16 | 1 << 22);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct NavDataOptions(u32);
+
+impl NavDataOptions {
+    pub const DEMO: NavDataOptions = NavDataOptions(1 << 0);
+    pub const TIME: NavDataOptions = NavDataOptions(1 << 1);
+    pub const RAW_MEASURES: NavDataOptions = NavDataOptions(1 << 2);
+    pub const PHYS_MEASURES: NavDataOptions = NavDataOptions(1 << 3);
+    pub const GYROS_OFFSETS: NavDataOptions = NavDataOptions(1 << 4);
+    pub const EULER_ANGLES: NavDataOptions = NavDataOptions(1 << 5);
+    pub const REFERENCES: NavDataOptions = NavDataOptions(1 << 6);
+    pub const TRIMS: NavDataOptions = NavDataOptions(1 << 7);
+    pub const RC_REFERENCES: NavDataOptions = NavDataOptions(1 << 8);
+    pub const PWM: NavDataOptions = NavDataOptions(1 << 9);
+    pub const ALTITUDE: NavDataOptions = NavDataOptions(1 << 10);
+    pub const VISION_RAW: NavDataOptions = NavDataOptions(1 << 11);
+    pub const VISION_OF: NavDataOptions = NavDataOptions(1 << 12);
+    pub const VISION: NavDataOptions = NavDataOptions(1 << 13);
+    pub const VISION_PERF: NavDataOptions = NavDataOptions(1 << 14);
+    pub const TRACKERS_SEND: NavDataOptions = NavDataOptions(1 << 15);
+    pub const VISION_DETECT: NavDataOptions = NavDataOptions(1 << 16);
+    pub const WATCHDOG: NavDataOptions = NavDataOptions(1 << 17);
+    pub const ADC_DATA_FRAME: NavDataOptions = NavDataOptions(1 << 18);
+    pub const VIDEO_STREAM: NavDataOptions = NavDataOptions(1 << 19);
+    pub const GAMES: NavDataOptions = NavDataOptions(1 << 20);
+    pub const PRESSURE_RAW: NavDataOptions = NavDataOptions(1 << 21);
+    pub const MAGNETO: NavDataOptions = NavDataOptions(1 << 22);
+    pub const WIND_SPEED: NavDataOptions = NavDataOptions(1 << 23);
+    pub const KALMAN_PRESSURE: NavDataOptions = NavDataOptions(1 << 24);
+    pub const HDVIDEO_STREAM: NavDataOptions = NavDataOptions(1 << 25);
+    pub const WIFI: NavDataOptions = NavDataOptions(1 << 26);
+    pub const GPS: NavDataOptions = NavDataOptions(1 << 27);
+
+    /// No option blocks
+    pub fn empty() -> NavDataOptions {
+        NavDataOptions(0)
+    }
+
+    /// Every option block this crate can decode
+    pub fn all() -> NavDataOptions {
+        NavDataOptions((1 << 28) - 1)
+    }
+
+    pub fn from_bits(bits: u32) -> NavDataOptions {
+        NavDataOptions(bits)
+    }
+
+    /// The option block with the given id, empty for ids which are not part
+    /// of the mask (e.g. the checksum)
+    pub fn from_id(id: u16) -> NavDataOptions {
+        if id < 32 {
+            NavDataOptions(1 << id)
+        } else {
+            NavDataOptions(0)
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every option block of `other` is in this set too
+    pub fn contains(&self, other: NavDataOptions) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for NavDataOptions {
+    type Output = NavDataOptions;
+
+    fn bitor(self, other: NavDataOptions) -> NavDataOptions {
+        NavDataOptions(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for NavDataOptions {
+    fn bitor_assign(&mut self, other: NavDataOptions) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for NavDataOptions {
+    type Output = NavDataOptions;
+
+    fn bitand(self, other: NavDataOptions) -> NavDataOptions {
+        NavDataOptions(self.0 & other.0)
+    }
+}
+
+impl Not for NavDataOptions {
+    type Output = NavDataOptions;
+
+    fn not(self) -> NavDataOptions {
+        NavDataOptions(!self.0)
+    }
+}
+
+/// Defines the option enum with one variant per option block and the
+/// snapshot struct with one optional field per option block.
+macro_rules! navdata_snapshot {
+    ($($field:ident: $ty:ident),* $(,)?) => {
+        /// A decoded option block of a navdata packet
+        // Packets are decoded one at a time, boxing the bigger blocks is not worth it
+        #[allow(clippy::large_enum_variant)]
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum NavDataOption {
+            $($ty($ty),)*
+            /// An option block with an id this crate does not know
+            Unknown { id: u16, data: Vec<u8> },
+        }
+
+        impl NavDataOption {
+            /// Decodes the option block with the given id, `crs` should
+            /// contain the data of the block (without the id and size).
+            pub fn read_from(id: u16, crs: &mut Cursor<&[u8]>) -> io::Result<NavDataOption> {
+                match id {
+                    $($ty::ID => Ok(NavDataOption::$ty($ty::read_from(crs)?)),)*
+                    _ => Ok(NavDataOption::Unknown {
+                        id,
+                        data: crs.get_ref()[crs.position() as usize..].to_vec(),
+                    }),
+                }
+            }
+
+            /// The id of the option block
+            pub fn id(&self) -> u16 {
+                match self {
+                    $(NavDataOption::$ty(_) => $ty::ID,)*
+                    NavDataOption::Unknown { id, .. } => *id,
+                }
+            }
+
+            /// Writes the whole option block: id, size and data
+            pub fn write_to(&self, out: &mut Vec<u8>) {
+                let start = out.len();
+                self.id().write_to(out);
+                0_u16.write_to(out);
+                match self {
+                    $(NavDataOption::$ty(value) => value.write_to(out),)*
+                    NavDataOption::Unknown { data, .. } => out.extend_from_slice(data),
+                }
+                let size = (out.len() - start) as u16;
+                out[start + 2..start + 4].copy_from_slice(&size.to_le_bytes());
+            }
+        }
+
+        /// Every value of a single navdata packet. An option block is None if
+        /// it was not part of the packet (e.g. everything but `demo` in demo
+        /// mode).
+        #[derive(Debug, Clone, Default, PartialEq)]
+        pub struct NavDataSnapshot {
+            pub header: Header,
+            $(pub $field: Option<$ty>,)*
+        }
+
+        impl NavDataSnapshot {
+            /// Stores the option block in the snapshot. Unknown blocks are
+            /// ignored.
+            pub fn apply(&mut self, option: &NavDataOption) {
+                match option {
+                    $(NavDataOption::$ty(value) => self.$field = Some(*value),)*
+                    NavDataOption::Unknown { .. } => {}
+                }
+            }
+
+            /// The option blocks of the snapshot, in the order of their ids
+            pub fn to_options(&self) -> Vec<NavDataOption> {
+                let mut options = Vec::new();
+                $(if let Some(option) = self.$field {
+                    options.push(NavDataOption::$ty(option));
+                })*
+                options
+            }
+
+            /// The option blocks which are present in the snapshot
+            pub fn options(&self) -> NavDataOptions {
+                let mut options = NavDataOptions::empty();
+                $(if self.$field.is_some() {
+                    options |= NavDataOptions::from_id($ty::ID);
+                })*
+                options
+            }
+
+            /// Puts every value of the snapshot into the navdata lookup.
+            pub fn insert_into(&self, options_map: &mut HashMap<String, NavDataValue>) {
+                self.header.insert_into(options_map);
+                $(if let Some(option) = &self.$field {
+                    option.insert_into(options_map);
+                })*
+            }
+        }
+    };
+}
+
+navdata_snapshot!(
+    demo: DemoData,
+    time: Time,
+    raw_measures: RawMeasures,
+    phys_measures: PhysMeasures,
+    gyros_offsets: GyrosOffsets,
+    euler_angles: EulerAngles,
+    references: References,
+    trims: Trims,
+    rc_references: RcReferences,
+    pwm: Pwm,
+    altitude: Altitude,
+    vision_raw: VisionRaw,
+    vision_of: VisionOf,
+    vision: Vision,
+    vision_perf: VisionPerf,
+    trackers_send: TrackersSend,
+    vision_detect: VisionDetect,
+    watchdog: Watchdog,
+    adc_data_frame: AdcDataFrame,
+    video_stream: VideoStream,
+    games: Games,
+    pressure_raw: PressureRaw,
+    magneto: Magneto,
+    wind_speed: WindSpeed,
+    kalman_pressure: KalmanPressure,
+    hdvideo_stream: HdVideoStream,
+    wifi: Wifi,
+    gps: Gps,
+);
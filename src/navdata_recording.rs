@@ -0,0 +1,217 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Every navdata recording starts with these bytes
+const RECORDING_MAGIC: &[u8; 8] = b"ARDRNAV1";
+/// The largest payload of a UDP datagram, longer records are corrupt
+const MAX_DATAGRAM_LEN: u32 = 65507;
+/// Slower replay speeds are raised to this, so the scaled timestamps of
+/// any recording still fit in a Duration
+const MIN_REPLAY_SPEED: f32 = 0.001;
+
+/// Where the navdata thread gets its datagrams from
+pub(crate) trait NavDataSource: Send {
+    /// Asks the drone to start sending navdata
+    fn wake_up(&mut self) -> io::Result<()>;
+    /// Waits a limited time for the next datagram, returns an error of kind
+    /// WouldBlock or TimedOut if none arrived.
+    fn recv(&mut self, buff: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Writes raw navdata datagrams to a file, each with the time elapsed since
+/// the start of the recording.
+///
+/// The file starts with `ARDRNAV1`, then every datagram is stored as the
+/// elapsed time in microseconds (u64), the length (u32) and the datagram
+/// itself, all little endian.
+pub struct NavDataRecorder {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl NavDataRecorder {
+    /// Creates (or truncates) the recording file
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<NavDataRecorder> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(RECORDING_MAGIC)?;
+        Ok(NavDataRecorder {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Appends a datagram, timestamped with the current time
+    pub fn write(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let elapsed = self.start.elapsed();
+        self.write_at(elapsed, datagram)
+    }
+
+    /// Appends a datagram with an explicit timestamp
+    pub fn write_at(&mut self, elapsed: Duration, datagram: &[u8]) -> io::Result<()> {
+        self.writer.write_u64::<LittleEndian>(elapsed.as_micros() as u64)?;
+        self.writer.write_u32::<LittleEndian>(datagram.len() as u32)?;
+        self.writer.write_all(datagram)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the datagrams of a file written by NavDataRecorder
+pub struct NavDataRecording {
+    reader: BufReader<File>,
+}
+
+impl NavDataRecording {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<NavDataRecording> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != RECORDING_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "not a navdata recording"));
+        }
+        Ok(NavDataRecording { reader })
+    }
+
+    /// Returns the next datagram with the time it was received (relative to
+    /// the start of the recording), or None at the end of the file. A
+    /// datagram longer than a UDP datagram can be is an InvalidData error.
+    pub fn next_datagram(&mut self) -> io::Result<Option<(Duration, Vec<u8>)>> {
+        let micros = match self.reader.read_u64::<LittleEndian>() {
+            Ok(micros) => micros,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let len = self.reader.read_u32::<LittleEndian>()?;
+        if len > MAX_DATAGRAM_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("navdata recording has a {} bytes datagram", len)));
+        }
+        let mut datagram = vec![0; len as usize];
+        self.reader.read_exact(&mut datagram)?;
+        Ok(Some((Duration::from_micros(micros), datagram)))
+    }
+}
+
+impl Iterator for NavDataRecording {
+    type Item = io::Result<(Duration, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_datagram().transpose()
+    }
+}
+
+/// Plays a recording back to the navdata thread with the original timing
+/// (scaled by `speed`).
+pub(crate) struct NavDataReplay {
+    recording: NavDataRecording,
+    speed: f32,
+    timeout: Duration,
+    start: Option<Instant>,
+    next: Option<(Duration, Vec<u8>)>,
+    finished: bool,
+}
+
+impl NavDataReplay {
+    /// A speed of 1.0 is real time, 2.0 is twice as fast. Speeds below
+    /// MIN_REPLAY_SPEED are raised to it, a speed which is not positive
+    /// replays in real time.
+    pub(crate) fn new(recording: NavDataRecording, speed: f32, timeout: Duration) -> NavDataReplay {
+        NavDataReplay {
+            recording,
+            speed: if speed > 0.0 { speed.max(MIN_REPLAY_SPEED) } else { 1.0 },
+            timeout,
+            start: None,
+            next: None,
+            finished: false,
+        }
+    }
+}
+
+impl NavDataSource for NavDataReplay {
+    fn wake_up(&mut self) -> io::Result<()> {
+        if self.start.is_none() {
+            self.start = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, buff: &mut [u8]) -> io::Result<usize> {
+        if self.next.is_none() && !self.finished {
+            match self.recording.next_datagram() {
+                Ok(Some(next)) => { self.next = Some(next); }
+                Ok(None) => { self.finished = true; }
+                Err(e) => {
+                    self.finished = true;
+                    return Err(e);
+                }
+            }
+        }
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let (elapsed, datagram) = match &self.next {
+            Some(next) => next,
+            None => {
+                thread::sleep(self.timeout);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "end of the recording"));
+            }
+        };
+
+        let due = start + elapsed.div_f32(self.speed);
+        let now = Instant::now();
+        if due > now {
+            let wait = due - now;
+            if wait > self.timeout {
+                thread::sleep(self.timeout);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "waiting for the next datagram"));
+            }
+            thread::sleep(wait);
+        }
+
+        let len = datagram.len().min(buff.len());
+        buff[..len].copy_from_slice(&datagram[..len]);
+        self.next = None;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn too_long_datagrams_are_invalid_data() {
+        let path = env::temp_dir().join(format!("navdata-too-long-{}.navdata", std::process::id()));
+        let mut data = RECORDING_MAGIC.to_vec();
+        data.extend_from_slice(&0_u64.to_le_bytes());
+        data.extend_from_slice(&(MAX_DATAGRAM_LEN + 1).to_le_bytes());
+        fs::write(&path, &data).unwrap();
+
+        let mut recording = NavDataRecording::open(&path).unwrap();
+        let error = recording.next_datagram().unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tiny_replay_speeds_do_not_overflow() {
+        let path = env::temp_dir().join(format!("navdata-slow-{}.navdata", std::process::id()));
+        let mut recorder = NavDataRecorder::create(&path).unwrap();
+        recorder.write_at(Duration::from_micros(u64::MAX), &[1, 2, 3]).unwrap();
+        recorder.flush().unwrap();
+
+        let recording = NavDataRecording::open(&path).unwrap();
+        let mut replay = NavDataReplay::new(recording, f32::MIN_POSITIVE, Duration::from_millis(1));
+        let mut buff = [0; 16];
+        let error = replay.recv(&mut buff).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}
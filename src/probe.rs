@@ -0,0 +1,121 @@
+use std::fmt;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+/// Default time to wait for every port of a connection probe
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What happened when connecting to a TCP port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortStatus {
+    /// The connection was accepted
+    Open,
+    /// The host answered but nothing listens on the port
+    Refused,
+    /// No answer before the timeout
+    TimedOut,
+    /// Any other error (no route to the host, unknown address...)
+    Failed(io::ErrorKind),
+}
+
+impl PortStatus {
+    /// Whether the host answered, even if the port is closed
+    pub fn host_answered(self) -> bool {
+        matches!(self, PortStatus::Open | PortStatus::Refused)
+    }
+}
+
+impl fmt::Display for PortStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortStatus::Open => write!(f, "open"),
+            PortStatus::Refused => write!(f, "refused"),
+            PortStatus::TimedOut => write!(f, "timed out"),
+            PortStatus::Failed(kind) => write!(f, "failed ({:?})", kind),
+        }
+    }
+}
+
+/// Which services of the drone answered a connection probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reachability {
+    pub ip: String,
+    /// FTP (port 21), serves the media and the firmware update
+    pub ftp: PortStatus,
+    /// Telnet (port 23), open on stock firmwares
+    pub telnet: PortStatus,
+    /// The control port (5559 by default) the config is read from, needed
+    /// by Drone::startup
+    pub control: PortStatus,
+}
+
+impl Reachability {
+    /// Whether anything answered at the drones IP
+    pub fn is_reachable(&self) -> bool {
+        self.ftp.host_answered() || self.telnet.host_answered() || self.control.host_answered()
+    }
+
+    /// Whether the drone can be started (the control port is open)
+    pub fn is_ready(&self) -> bool {
+        self.control == PortStatus::Open
+    }
+}
+
+impl fmt::Display for Reachability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ftp {}, telnet {}, control {}",
+               self.ip, self.ftp, self.telnet, self.control)
+    }
+}
+
+/// Tries to connect to a TCP port, waiting at most `timeout`
+pub fn probe_port(ip: &str, port: u32, timeout: Duration) -> PortStatus {
+    let port = match u16::try_from(port) {
+        Ok(port) => port,
+        Err(_) => return PortStatus::Failed(io::ErrorKind::InvalidInput),
+    };
+    let address = match (ip, port).to_socket_addrs().map(|mut a| a.next()) {
+        Ok(Some(address)) => address,
+        Ok(None) => return PortStatus::Failed(io::ErrorKind::NotFound),
+        Err(e) => return PortStatus::Failed(e.kind()),
+    };
+    match TcpStream::connect_timeout(&address, timeout) {
+        Ok(_) => PortStatus::Open,
+        Err(e) => match e.kind() {
+            io::ErrorKind::ConnectionRefused => PortStatus::Refused,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => PortStatus::TimedOut,
+            kind => PortStatus::Failed(kind),
+        },
+    }
+}
+
+/// Checks the FTP, telnet and control ports of the drone at the same time,
+/// so it takes at most `timeout`.
+/// ```no_run
+/// use parrot_ar_drone::*;
+/// use std::time::Duration;
+///
+/// let reachability = probe("192.168.1.1", 5559, Duration::from_millis(500));
+/// if !reachability.is_reachable() {
+///     println!("Not connected to the drones network?");
+/// }
+/// println!("{}", reachability);
+/// ```
+pub fn probe(ip: &str, ctl_port: u32, timeout: Duration) -> Reachability {
+    let (ftp, telnet, control) = thread::scope(|s| {
+        let ftp = s.spawn(|| probe_port(ip, 21, timeout));
+        let telnet = s.spawn(|| probe_port(ip, 23, timeout));
+        let control = probe_port(ip, ctl_port, timeout);
+        let failed = PortStatus::Failed(io::ErrorKind::Other);
+        (ftp.join().unwrap_or(failed), telnet.join().unwrap_or(failed), control)
+    });
+    Reachability {
+        ip: String::from(ip),
+        ftp,
+        telnet,
+        control,
+    }
+}
@@ -0,0 +1,362 @@
+use std::io::{self, Read};
+use std::net::{TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::at_parser::{parse_at_commands, ParsedAtCommand};
+use crate::communication::{Communication, bind_udp};
+use crate::error::DroneError;
+use crate::navdata_recording::NavDataSource;
+
+/// How long the receiving methods of a transport wait before giving up
+pub const TRANSPORT_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The links to the drone. The command, navdata and config threads share
+/// one transport, so every method takes `&self`.
+///
+/// The receiving methods wait at most TRANSPORT_READ_TIMEOUT and then
+/// return an error of kind WouldBlock or TimedOut.
+pub trait Transport: Send + Sync {
+    /// Sends a datagram of AT commands
+    fn send_at(&self, datagram: &[u8]) -> io::Result<()>;
+    /// Asks the drone to (re)start sending navdata
+    fn wake_up_navdata(&self) -> io::Result<()>;
+    /// Receives a navdata datagram
+    fn recv_navdata(&self, buff: &mut [u8]) -> io::Result<usize>;
+    /// Reads from the control stream (the config sent after `CTRL 4`)
+    fn read_control(&self, buff: &mut [u8]) -> io::Result<usize>;
+    /// Reads from the video stream
+    fn read_video(&self, buff: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Whether the error only means that nothing arrived in time
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+/// The navdata thread reads from a transport through this
+pub(crate) struct TransportNavData(pub(crate) Arc<dyn Transport>);
+
+impl NavDataSource for TransportNavData {
+    fn wake_up(&mut self) -> io::Result<()> {
+        self.0.wake_up_navdata()
+    }
+
+    fn recv(&mut self, buff: &mut [u8]) -> io::Result<usize> {
+        self.0.recv_navdata(buff)
+    }
+}
+
+/// The real links: UDP for commands, navdata and video, TCP for the control
+/// stream. The video socket is only opened when it is first read.
+pub struct UdpTransport {
+    commands: UdpSocket,
+    navdata: UdpSocket,
+    control: TcpStream,
+    video: Mutex<Option<UdpSocket>>,
+    /// Local address and port of the video socket, and the drones one
+    video_local: (String, u32),
+    video_remote: String,
+}
+
+impl UdpTransport {
+    /// Opens the links with the addresses and ports of `communication`
+    pub fn connect(communication: &Communication) -> Result<UdpTransport, DroneError> {
+        let commands = communication.get_cmd_udp_connection()?;
+        commands.set_nonblocking(false)?;
+
+        let navdata = communication.get_navdata_udp_connection()?;
+        navdata.set_nonblocking(false)?;
+        navdata.set_read_timeout(Some(TRANSPORT_READ_TIMEOUT))?;
+
+        let control = communication.get_ctl_tcp_connection()?;
+        control.set_read_timeout(Some(TRANSPORT_READ_TIMEOUT))?;
+
+        Ok(UdpTransport {
+            commands,
+            navdata,
+            control,
+            video: Mutex::new(None),
+            video_local: (communication.bind_ip.clone(), communication.local_video_port),
+            video_remote: format!("{}:{}", communication.drone_ip, communication.video_port),
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_at(&self, datagram: &[u8]) -> io::Result<()> {
+        match self.commands.send(datagram) {
+            Ok(_) => Ok(()),
+            // ICMP port unreachable, e.g. while the drone reboots
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn wake_up_navdata(&self) -> io::Result<()> {
+        self.navdata.send(&[1, 0, 0, 0]).map(|_| ())
+    }
+
+    fn recv_navdata(&self, buff: &mut [u8]) -> io::Result<usize> {
+        self.navdata.recv(buff)
+    }
+
+    fn read_control(&self, buff: &mut [u8]) -> io::Result<usize> {
+        (&self.control).read(buff)
+    }
+
+    fn read_video(&self, buff: &mut [u8]) -> io::Result<usize> {
+        let mut video = self.video.lock().unwrap_or_else(PoisonError::into_inner);
+        if video.is_none() {
+            let (ip, port) = &self.video_local;
+            let socket = bind_udp(ip, *port)
+                .map_err(|e| io::Error::new(io::ErrorKind::AddrInUse, e.to_string()))?;
+            socket.connect(&self.video_remote)?;
+            socket.set_read_timeout(Some(TRANSPORT_READ_TIMEOUT))?;
+            *video = Some(socket);
+        }
+        match &*video {
+            Some(socket) => socket.recv(buff),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+}
+
+/// One direction of an in-memory stream, chunks are split as needed
+struct MemoryStream {
+    receiver: Mutex<Receiver<Vec<u8>>>,
+    pending: Mutex<Vec<u8>>,
+}
+
+impl MemoryStream {
+    fn new(receiver: Receiver<Vec<u8>>) -> MemoryStream {
+        MemoryStream {
+            receiver: Mutex::new(receiver),
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn read(&self, buff: &mut [u8]) -> io::Result<usize> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        if pending.is_empty() {
+            *pending = recv_timeout(&self.receiver)?;
+        }
+        let len = pending.len().min(buff.len());
+        buff[..len].copy_from_slice(&pending[..len]);
+        pending.drain(..len);
+        Ok(len)
+    }
+}
+
+fn recv_timeout(receiver: &Mutex<Receiver<Vec<u8>>>) -> io::Result<Vec<u8>> {
+    let receiver = receiver.lock().unwrap_or_else(PoisonError::into_inner);
+    match receiver.recv_timeout(TRANSPORT_READ_TIMEOUT) {
+        Ok(data) => Ok(data),
+        Err(RecvTimeoutError::Timeout) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        Err(RecvTimeoutError::Disconnected) => Err(io::Error::from(io::ErrorKind::NotConnected)),
+    }
+}
+
+/// A transport which talks to a MemoryPeer instead of a drone, for tests.
+/// ```
+/// use parrot_ar_drone::*;
+/// use std::sync::Arc;
+///
+/// let (transport, peer) = MemoryTransport::new();
+/// let mut drone = DroneBuilder::new()
+///     .transport(Arc::new(transport))
+///     .show_commands(false)
+///     .debug(false)
+///     .build();
+/// drone.startup().unwrap();
+/// drone.takeoff();
+///
+/// let takeoff = peer.wait_for_command(|c| c.name == "REF" && c.int(0) == Some(290718208));
+/// assert!(takeoff.is_some());
+/// drone.shutdown().unwrap();
+/// ```
+pub struct MemoryTransport {
+    at: Sender<Vec<u8>>,
+    /// How many of the next AT datagrams fail, set by the peer
+    failing_sends: Arc<AtomicUsize>,
+    wake_ups: Sender<()>,
+    navdata: Mutex<Receiver<Vec<u8>>>,
+    control: MemoryStream,
+    video: MemoryStream,
+}
+
+/// The drone side of a MemoryTransport: it sees the AT datagrams and sends
+/// navdata, control and video data.
+/// ```
+/// use parrot_ar_drone::*;
+/// use std::sync::Arc;
+///
+/// let (transport, peer) = MemoryTransport::new();
+/// let mut drone = DroneBuilder::new()
+///     .transport(Arc::new(transport))
+///     .show_commands(false)
+///     .debug(false)
+///     .build();
+/// let navdata = drone.subscribe_navdata();
+/// peer.send_control(b"general:num_version_soft = 2.4.8\n").unwrap();
+/// drone.startup().unwrap();
+///
+/// let mut packet = Vec::new();
+/// packet.extend_from_slice(&NAVDATA_HEADER.to_le_bytes());
+/// packet.extend_from_slice(&1_u32.to_le_bytes()); // drone state: flying
+/// packet.extend_from_slice(&7_u32.to_le_bytes()); // sequence number
+/// packet.extend_from_slice(&0_u32.to_le_bytes()); // vision flag
+/// let checksum: u32 = packet.iter().map(|b| u32::from(*b)).sum();
+/// packet.extend_from_slice(&[0xFF, 0xFF, 8, 0]);
+/// packet.extend_from_slice(&checksum.to_le_bytes());
+/// peer.send_navdata(&packet).unwrap();
+///
+/// let snapshot = navdata.recv().unwrap();
+/// assert_eq!(snapshot.header.seq_num, 7);
+/// assert!(drone.drone_state().unwrap().flying());
+/// assert_eq!(drone.get_offline_config("general:num_version_soft").unwrap(),
+///            Some(String::from("2.4.8")));
+/// drone.shutdown().unwrap();
+/// ```
+pub struct MemoryPeer {
+    at: Receiver<Vec<u8>>,
+    failing_sends: Arc<AtomicUsize>,
+    wake_ups: Receiver<()>,
+    navdata: Sender<Vec<u8>>,
+    control: Sender<Vec<u8>>,
+    video: Sender<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    /// Returns a connected transport and peer
+    pub fn new() -> (MemoryTransport, MemoryPeer) {
+        let (at_s, at_r) = mpsc::channel();
+        let (wake_s, wake_r) = mpsc::channel();
+        let (navdata_s, navdata_r) = mpsc::channel();
+        let (control_s, control_r) = mpsc::channel();
+        let (video_s, video_r) = mpsc::channel();
+        let failing_sends = Arc::new(AtomicUsize::new(0));
+        let transport = MemoryTransport {
+            at: at_s,
+            failing_sends: failing_sends.clone(),
+            wake_ups: wake_s,
+            navdata: Mutex::new(navdata_r),
+            control: MemoryStream::new(control_r),
+            video: MemoryStream::new(video_r),
+        };
+        let peer = MemoryPeer {
+            at: at_r,
+            failing_sends,
+            wake_ups: wake_r,
+            navdata: navdata_s,
+            control: control_s,
+            video: video_s,
+        };
+        (transport, peer)
+    }
+}
+
+fn peer_gone() -> io::Error {
+    io::Error::from(io::ErrorKind::BrokenPipe)
+}
+
+impl Transport for MemoryTransport {
+    fn send_at(&self, datagram: &[u8]) -> io::Result<()> {
+        let failing = self.failing_sends.fetch_update(Ordering::SeqCst, Ordering::SeqCst,
+                                                      |n| n.checked_sub(1));
+        if failing.is_ok() {
+            return Err(io::Error::other("send failure injected by the peer"));
+        }
+        self.at.send(datagram.to_vec()).map_err(|_| peer_gone())
+    }
+
+    fn wake_up_navdata(&self) -> io::Result<()> {
+        self.wake_ups.send(()).map_err(|_| peer_gone())
+    }
+
+    fn recv_navdata(&self, buff: &mut [u8]) -> io::Result<usize> {
+        let datagram = recv_timeout(&self.navdata)?;
+        let len = datagram.len().min(buff.len());
+        buff[..len].copy_from_slice(&datagram[..len]);
+        Ok(len)
+    }
+
+    fn read_control(&self, buff: &mut [u8]) -> io::Result<usize> {
+        self.control.read(buff)
+    }
+
+    fn read_video(&self, buff: &mut [u8]) -> io::Result<usize> {
+        self.video.read(buff)
+    }
+}
+
+impl MemoryPeer {
+    /// Returns the next AT datagram, waiting at most `timeout`
+    pub fn recv_at(&self, timeout: Duration) -> Option<String> {
+        self.at.recv_timeout(timeout).ok()
+            .map(|d| String::from_utf8_lossy(&d).into_owned())
+    }
+
+    /// Returns every AT datagram sent so far
+    pub fn at_datagrams(&self) -> Vec<String> {
+        self.at.try_iter()
+            .map(|d| String::from_utf8_lossy(&d).into_owned())
+            .collect()
+    }
+
+    /// Waits (at most 2 seconds) for an AT datagram matching `predicate`,
+    /// the others are dropped
+    pub fn wait_for_at<F: Fn(&str) -> bool>(&self, predicate: F) -> Option<String> {
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        loop {
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            match self.recv_at(deadline - now) {
+                Some(datagram) if predicate(&datagram) => return Some(datagram),
+                Some(_) => {}
+                None => return None,
+            }
+        }
+    }
+
+    /// Waits (at most 2 seconds) for an AT command matching `predicate`,
+    /// the commands before it are dropped
+    pub fn wait_for_command<F>(&self, predicate: F) -> Option<ParsedAtCommand>
+        where F: Fn(&ParsedAtCommand) -> bool {
+        let find = |datagram: &str| parse_at_commands(datagram.as_bytes())
+            .unwrap_or_default()
+            .into_iter()
+            .find(|c| predicate(c));
+        self.wait_for_at(|datagram| find(datagram).is_some())
+            .and_then(|datagram| find(&datagram))
+    }
+
+    /// Makes the next `count` AT datagrams fail to send, they are lost
+    pub fn fail_sends(&self, count: usize) {
+        self.failing_sends.store(count, Ordering::SeqCst);
+    }
+
+    /// How many wake-up datagrams the navdata component sent so far
+    pub fn navdata_wake_ups(&self) -> usize {
+        self.wake_ups.try_iter().count()
+    }
+
+    /// Sends a navdata datagram (see parse_navdata for the format)
+    pub fn send_navdata(&self, datagram: &[u8]) -> io::Result<()> {
+        self.navdata.send(datagram.to_vec()).map_err(|_| peer_gone())
+    }
+
+    /// Sends config lines on the control stream, e.g.
+    /// `"general:navdata_demo = TRUE\n"`
+    pub fn send_control(&self, data: &[u8]) -> io::Result<()> {
+        self.control.send(data.to_vec()).map_err(|_| peer_gone())
+    }
+
+    pub fn send_video(&self, data: &[u8]) -> io::Result<()> {
+        self.video.send(data.to_vec()).map_err(|_| peer_gone())
+    }
+}